@@ -0,0 +1,505 @@
+// * Local Crystal server for running the capture without the vendor feed
+// * Usage: cargo run --bin crystal-sim -- [--listen 127.0.0.1:8184] [--rate 1000] [--replay file]
+// *        [--username user] [--password pass] [--reject-auth] [--reject-symbols a,b]
+// *        [--disconnect-after secs] [--stall-after secs] [--stall-for secs]
+// *        [--partial-lines probability] [--corrupt-lines probability]
+use chrono::Local;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{interval, sleep, Duration, Instant};
+
+const TICKS_PER_SECOND: u64 = 100;
+const BOOK_SNAPSHOT_DEPTH: usize = 5;
+const MAX_BOOK_DEPTH: usize = 20;
+
+#[derive(Debug, Clone)]
+struct Options {
+    listen: String,
+    username: String,
+    password: String,
+    rate: u64,
+    replay: Option<String>,
+    reject_auth: bool,
+    reject_symbols: BTreeSet<String>,
+    disconnect_after: Option<u64>,
+    stall_after: Option<u64>,
+    stall_for: u64,
+    partial_lines: f64,
+    corrupt_lines: f64,
+}
+
+impl Options {
+    fn parse() -> Result<Options, Box<dyn Error>> {
+        let mut options = Options {
+            listen: "127.0.0.1:8184".to_string(),
+            username: "user".to_string(),
+            password: "pass".to_string(),
+            rate: 1000,
+            replay: None,
+            reject_auth: false,
+            reject_symbols: BTreeSet::new(),
+            disconnect_after: None,
+            stall_after: None,
+            stall_for: 60,
+            partial_lines: 0.0,
+            corrupt_lines: 0.0,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--reject-auth" {
+                options.reject_auth = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Error: missing value for {}", arg))?;
+            match arg.as_str() {
+                "--listen" => options.listen = value,
+                "--username" => options.username = value,
+                "--password" => options.password = value,
+                "--rate" => options.rate = value.parse()?,
+                "--replay" => options.replay = Some(value),
+                "--reject-symbols" => {
+                    options.reject_symbols = value
+                        .split(',')
+                        .map(|symbol| symbol.trim().to_uppercase())
+                        .filter(|symbol| !symbol.is_empty())
+                        .collect()
+                }
+                "--disconnect-after" => options.disconnect_after = Some(value.parse()?),
+                "--stall-after" => options.stall_after = Some(value.parse()?),
+                "--stall-for" => options.stall_for = value.parse()?,
+                "--partial-lines" => options.partial_lines = value.parse()?,
+                "--corrupt-lines" => options.corrupt_lines = value.parse()?,
+                other => return Err(format!("Error: unknown option {}", other).into()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Feed {
+    Book,
+    Trades,
+    Quotes,
+}
+
+impl Feed {
+    // * B - book, V - trades, T - quotes
+    fn from_tag(tag: &str) -> Option<Feed> {
+        match tag {
+            "B" => Some(Feed::Book),
+            "V" => Some(Feed::Trades),
+            "T" => Some(Feed::Quotes),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Subscriptions {
+    symbols: BTreeSet<(Feed, String)>,
+    // * Book snapshots owed to new BQT subscriptions
+    snapshots: Vec<String>,
+    // * Error replies owed to rejected commands
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Market {
+    price: HashMap<String, f64>,
+    book_depth: HashMap<(String, bool), usize>,
+    next_id: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::parse() {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let replay = match &options.replay {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(content) => Arc::new(
+                content
+                    .lines()
+                    .map(strip_capture_timestamp)
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.to_string())
+                    .collect::<Vec<_>>(),
+            ),
+            Err(e) => {
+                eprintln!("Error: read replay file {} - {:?}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => Arc::new(Vec::new()),
+    };
+
+    let listener = match TcpListener::bind(&options.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error: bind {} - {:?}", options.listen, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Crystal simulator listening on {}", options.listen);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                println!("Connection from {}", peer);
+                let options = Arc::clone(&options);
+                let replay = Arc::clone(&replay);
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, options, replay).await {
+                        println!("Connection from {} closed - {}", peer, e);
+                    } else {
+                        println!("Connection from {} closed", peer);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Error: accept - {:?}", e),
+        }
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    options: Arc<Options>,
+    replay: Arc<Vec<String>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    if !login(&mut reader, &mut writer, &options).await? {
+        return Ok(());
+    }
+
+    let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+    let commands = tokio::spawn(read_commands(
+        reader,
+        Arc::clone(&subscriptions),
+        Arc::clone(&options),
+    ));
+    let result = stream_data(&mut writer, &subscriptions, &options, &replay).await;
+    commands.abort();
+    result
+}
+
+async fn login(
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+    options: &Options,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut line = String::new();
+    writer.write_all(b"Connecting...\r\n").await?;
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(false);
+    }
+
+    writer.write_all(b"Username: ").await?;
+    line.clear();
+    reader.read_line(&mut line).await?;
+    let username = line.trim().to_string();
+
+    writer.write_all(b"Password: ").await?;
+    line.clear();
+    reader.read_line(&mut line).await?;
+    let password = line.trim().to_string();
+
+    if options.reject_auth || username != options.username || password != options.password {
+        println!("Rejecting login for {}", username);
+        writer
+            .write_all(b"\r\nInvalid login or password\r\nUsername: ")
+            .await?;
+        return Ok(false);
+    }
+    writer.write_all(b"\r\nYou are connected\r\n").await?;
+    println!("Authenticated {}", username);
+    Ok(true)
+}
+
+async fn read_commands(
+    mut reader: BufReader<OwnedReadHalf>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    options: Arc<Options>,
+) {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(command), Some(symbol)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let symbol = symbol.to_uppercase();
+        let (feed, subscribe) = match command.to_uppercase().as_str() {
+            "BQT" => (Feed::Book, true),
+            "GQT" => (Feed::Trades, true),
+            "SQT" => (Feed::Quotes, true),
+            "UBQ" => (Feed::Book, false),
+            "UGQ" => (Feed::Trades, false),
+            "USQ" => (Feed::Quotes, false),
+            other => {
+                println!("Ignoring command {}", other);
+                continue;
+            }
+        };
+
+        let mut subscriptions = subscriptions.lock().unwrap();
+        if !subscribe {
+            subscriptions.symbols.remove(&(feed, symbol));
+        } else if options.reject_symbols.contains(&symbol) {
+            subscriptions
+                .errors
+                .push(format!("E:{}:1:Invalid symbol!", symbol));
+        } else if subscriptions.symbols.insert((feed, symbol.clone())) && feed == Feed::Book {
+            subscriptions.snapshots.push(symbol);
+        }
+    }
+}
+
+async fn stream_data(
+    writer: &mut OwnedWriteHalf,
+    subscriptions: &Mutex<Subscriptions>,
+    options: &Options,
+    replay: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let started = Instant::now();
+    let mut rng = StdRng::from_entropy();
+    let mut market = Market::default();
+    let mut replay_position = 0;
+    let mut stalled = false;
+    let mut ticker = interval(Duration::from_millis(1000 / TICKS_PER_SECOND));
+    let per_tick = (options.rate / TICKS_PER_SECOND).max(1);
+
+    loop {
+        ticker.tick().await;
+        let elapsed = started.elapsed().as_secs();
+
+        if options
+            .disconnect_after
+            .is_some_and(|after| elapsed >= after)
+        {
+            println!("Injecting disconnect after {}s", elapsed);
+            return Ok(());
+        }
+        if !stalled && options.stall_after.is_some_and(|after| elapsed >= after) {
+            stalled = true;
+            println!("Injecting stall for {}s", options.stall_for);
+            sleep(Duration::from_secs(options.stall_for)).await;
+        }
+
+        let mut lines = Vec::new();
+        {
+            let mut subscriptions = subscriptions.lock().unwrap();
+            lines.append(&mut subscriptions.errors);
+            for symbol in std::mem::take(&mut subscriptions.snapshots) {
+                lines.extend(market.book_snapshot(&symbol, &mut rng));
+            }
+            let subscribed: Vec<(Feed, String)> = subscriptions.symbols.iter().cloned().collect();
+            if !subscribed.is_empty() {
+                for _ in 0..per_tick {
+                    let line = if replay.is_empty() {
+                        let (feed, symbol) = subscribed.choose(&mut rng).unwrap();
+                        market.next_line(*feed, symbol, &mut rng)
+                    } else {
+                        match next_replay_line(replay, &mut replay_position, &subscriptions.symbols)
+                        {
+                            Some(line) => line,
+                            None => break,
+                        }
+                    };
+                    lines.push(line);
+                }
+            }
+        }
+
+        for line in lines {
+            // * Cut on bytes, recorded lines may hold multi-byte characters
+            let mut line = line.into_bytes();
+            if rng.gen_bool(options.corrupt_lines.clamp(0.0, 1.0)) {
+                let cut = rng.gen_range(1..line.len().max(2));
+                line.truncate(cut);
+            }
+            line.extend_from_slice(b"\r\n");
+            if rng.gen_bool(options.partial_lines.clamp(0.0, 1.0)) {
+                let split = rng.gen_range(1..line.len());
+                writer.write_all(&line[..split]).await?;
+                writer.flush().await?;
+                sleep(Duration::from_millis(rng.gen_range(1..50))).await;
+                writer.write_all(&line[split..]).await?;
+            } else {
+                writer.write_all(&line).await?;
+            }
+        }
+    }
+}
+
+// * Recorded lines are sent in order for the subscribed symbols, looping at the end
+fn next_replay_line(
+    replay: &[String],
+    position: &mut usize,
+    symbols: &BTreeSet<(Feed, String)>,
+) -> Option<String> {
+    for _ in 0..replay.len() {
+        let line = &replay[*position];
+        *position = (*position + 1) % replay.len();
+        let mut fields = line.split(':');
+        let feed = fields.next().and_then(Feed::from_tag);
+        let symbol = fields.next().map(|symbol| symbol.to_uppercase());
+        if let (Some(feed), Some(symbol)) = (feed, symbol) {
+            if symbols.contains(&(feed, symbol)) {
+                return Some(line.clone());
+            }
+        }
+    }
+    None
+}
+
+// * Accepts raw Crystal lines and crystal-md capture lines ([<sequence> ]HH:MM:SS.mmm <line>),
+// * or <sequence> <UTC time> +<offset> <exchange time> <line> with CHITA_TIMESTAMP_FORMAT=full
+fn strip_capture_timestamp(line: &str) -> &str {
+    let digits = line.bytes().take_while(|b| b.is_ascii_digit()).count();
+    let rest = match line.as_bytes().get(digits) {
+        Some(b' ') if digits > 0 => &line[digits + 1..],
+        _ => line,
+    };
+    let bytes = rest.as_bytes();
+    if bytes.len() > 31 && bytes[10] == b'T' && bytes[29] == b'Z' && bytes[31] == b'+' {
+        if let Some(line) = rest.splitn(4, ' ').nth(3) {
+            return line.trim_end();
+        }
+    }
+    let is_capture = bytes.len() > 13
+        && bytes[2] == b':'
+        && bytes[5] == b':'
+        && bytes[8] == b'.'
+        && bytes[12] == b' ';
+    let line = if is_capture { &rest[13..] } else { line };
+    line.trim_end()
+}
+
+impl Market {
+    fn price(&mut self, symbol: &str, rng: &mut StdRng) -> f64 {
+        let price = self
+            .price
+            .entry(symbol.to_string())
+            .or_insert_with(|| rng.gen_range(5.0..100.0));
+        *price = (*price * (1.0 + rng.gen_range(-0.001..0.001))).max(0.01);
+        (*price * 100.0).round() / 100.0
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn next_line(&mut self, feed: Feed, symbol: &str, rng: &mut StdRng) -> String {
+        let now = Local::now();
+        match feed {
+            Feed::Quotes => {
+                let price = self.price(symbol, rng);
+                format!(
+                    "T:{}:{}:2:{:.2}:3:{:.2}:4:{:.2}:7:{}!",
+                    symbol,
+                    now.format("%H%M%S"),
+                    price,
+                    price - 0.01,
+                    price + 0.01,
+                    rng.gen_range(100..100000)
+                )
+            }
+            Feed::Trades => {
+                let price = self.price(symbol, rng);
+                format!(
+                    "V:{}:A:{}:{:.2}:{}:{}:{}:{}:0:{}!",
+                    symbol,
+                    now.format("%H%M%S%3f"),
+                    price,
+                    rng.gen_range(1..200),
+                    rng.gen_range(1..200),
+                    rng.gen_range(1..100) * 100,
+                    self.next_id(),
+                    if rng.gen_bool(0.5) { "A" } else { "V" }
+                )
+            }
+            Feed::Book => {
+                let bid = rng.gen_bool(0.5);
+                let depth = self
+                    .book_depth
+                    .get(&(symbol.to_string(), bid))
+                    .copied()
+                    .unwrap_or(0);
+                if depth > 0 && (depth >= MAX_BOOK_DEPTH || rng.gen_bool(0.4)) {
+                    self.book_depth.insert((symbol.to_string(), bid), depth - 1);
+                    format!(
+                        "B:{}:D:1:{}:{}!",
+                        symbol,
+                        side(bid),
+                        rng.gen_range(0..depth)
+                    )
+                } else {
+                    let position = rng.gen_range(0..=depth);
+                    self.book_depth.insert((symbol.to_string(), bid), depth + 1);
+                    self.add_order(symbol, bid, position, rng)
+                }
+            }
+        }
+    }
+
+    fn add_order(&mut self, symbol: &str, bid: bool, position: usize, rng: &mut StdRng) -> String {
+        let price = self.price(symbol, rng);
+        let offset = 0.01 * (position as f64 + 1.0);
+        format!(
+            "B:{}:A:{}:{}:{:.2}:{}:{}:{}:{}:L!",
+            symbol,
+            position,
+            side(bid),
+            if bid { price - offset } else { price + offset },
+            rng.gen_range(1..100) * 100,
+            rng.gen_range(1..200),
+            Local::now().format("%m%d%H%M"),
+            self.next_id()
+        )
+    }
+
+    fn book_snapshot(&mut self, symbol: &str, rng: &mut StdRng) -> Vec<String> {
+        let mut lines = Vec::with_capacity(BOOK_SNAPSHOT_DEPTH * 2 + 1);
+        for bid in [true, false] {
+            for position in 0..BOOK_SNAPSHOT_DEPTH {
+                lines.push(self.add_order(symbol, bid, position, rng));
+            }
+            self.book_depth
+                .insert((symbol.to_string(), bid), BOOK_SNAPSHOT_DEPTH);
+        }
+        lines.push(format!("B:{}:E!", symbol));
+        lines
+    }
+}
+
+// * A - buy (compra), V - sell (venda)
+fn side(bid: bool) -> &'static str {
+    if bid {
+        "A"
+    } else {
+        "V"
+    }
+}
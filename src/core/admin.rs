@@ -0,0 +1,228 @@
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use super::subscriptions::{self, ALL_FEEDS};
+use super::writer::NUM_WRITERS;
+use super::{app, crystal};
+use crate::helpers::errors::report_error;
+use crate::helpers::health;
+use crate::helpers::metrics::METRICS;
+
+// * Operator actions on the running service, every request needs Authorization: Bearer <token>
+pub async fn serve(addr: String, token: String) {
+    if addr.is_empty() {
+        return;
+    }
+    if token.is_empty() {
+        warn!(addr = %addr, "Admin API disabled, CHITA_ADMIN_TOKEN is empty");
+        return;
+    }
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            report_error(&format!("Error: bind admin API {} - {:?}", addr, e));
+            return;
+        }
+    };
+    info!(addr = %addr, "Serving admin API");
+
+    let app = Router::new()
+        .route("/status", get(status))
+        .route("/stats", get(stats))
+        .route("/session/start", post(start))
+        .route("/session/stop", post(stop))
+        .route("/upload", post(upload))
+        .route("/subscriptions", get(list_subscriptions))
+        .route(
+            "/subscriptions/{symbol}",
+            put(subscribe).delete(unsubscribe),
+        )
+        .layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            authorize,
+        ));
+    if let Err(e) = axum::serve(listener, app).await {
+        report_error(&format!("Error: admin API {} - {:?}", addr, e));
+    }
+}
+
+async fn authorize(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| same(given.as_bytes(), token.as_bytes()));
+    if !authorized {
+        warn!(path = %request.uri().path(), "Admin request without a valid token");
+        return reply(StatusCode::UNAUTHORIZED, "missing or invalid bearer token");
+    }
+    next.run(request).await
+}
+
+// * Looks at every byte, so the time taken does not tell how much of the token matched
+fn same(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn reply(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "message": message }))).into_response()
+}
+
+// * capture is true from the scheduled or forced start until the session ends
+async fn status() -> Json<Value> {
+    Json(json!({
+        "capture": app::running(),
+        "session": crystal::running(),
+        "uploading": crystal::uploading(),
+        "health": health::report().body,
+    }))
+}
+
+async fn start() -> Response {
+    if app::running() {
+        return reply(StatusCode::CONFLICT, "capture is already running");
+    }
+    info!("Admin start");
+    tokio::spawn(app::run());
+    reply(StatusCode::ACCEPTED, "capture starting")
+}
+
+// * Stops the capture, still preparing or running, and uploads content/ like the scheduled stop
+async fn stop() -> Response {
+    if !app::running() {
+        return reply(
+            StatusCode::CONFLICT,
+            "no capture is running, use /upload to send content/",
+        );
+    }
+    info!("Admin stop");
+    tokio::spawn(app::stop());
+    reply(StatusCode::ACCEPTED, "stopping the session and uploading")
+}
+
+// * Retries the upload of content/ once no capture is writing to it
+async fn upload() -> Response {
+    if app::running() {
+        return reply(
+            StatusCode::CONFLICT,
+            "capture is running, use /session/stop to stop and upload",
+        );
+    }
+    if crystal::uploading() {
+        return reply(StatusCode::CONFLICT, "an upload is already in progress");
+    }
+    info!("Admin upload");
+    tokio::spawn(crystal::upload());
+    reply(StatusCode::ACCEPTED, "uploading content/")
+}
+
+async fn stats() -> Response {
+    let Some(stats) = crystal::stats() else {
+        return reply(StatusCode::CONFLICT, "no Crystal session is running");
+    };
+    let load = |counter: &AtomicUsize| counter.load(Ordering::SeqCst);
+    let writers: Vec<Value> = (0..NUM_WRITERS)
+        .map(|writer| {
+            let queued = METRICS
+                .queued_batches
+                .with_label_values(&[&writer.to_string()])
+                .get();
+            json!({ "writer": writer, "queued_batches": queued })
+        })
+        .collect();
+    Json(json!({
+        "lines_queued": load(&stats.lines_queued),
+        "lines_sent": load(&stats.lines_sent),
+        "malformed_quotes": load(&stats.malformed_quotes),
+        "malformed_trades": load(&stats.malformed_trades),
+        "reconnects": load(&stats.reconnects),
+        "bytes_buffered": load(&stats.bytes_buffered),
+        "lines_spilled": load(&stats.lines_spilled),
+        "spool_bytes": load(&stats.spool_bytes),
+        "lines_dropped": load(&stats.lines_dropped),
+        "events_dropped": load(&stats.events_dropped),
+        "unattributed_errors": load(&stats.unattributed_errors),
+        "writers": writers,
+    }))
+    .into_response()
+}
+
+async fn list_subscriptions() -> Response {
+    let Some(list) = crystal::subscriptions() else {
+        return reply(StatusCode::CONFLICT, "no Crystal session is running");
+    };
+    let list: Vec<Value> = list
+        .into_iter()
+        .map(|(symbol, feeds)| {
+            let feeds: Map<String, Value> = feeds
+                .into_iter()
+                .map(|(feed, status)| (feed.to_string(), json!(status.to_string())))
+                .collect();
+            json!({ "symbol": symbol, "feeds": feeds })
+        })
+        .collect();
+    Json(json!({ "subscriptions": list })).into_response()
+}
+
+// * Format: PUT /subscriptions/<symbol>[?feeds=book,trades,quotes], all feeds by default
+async fn subscribe(
+    Path(symbol): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    update(&symbol, query.get("feeds"), true)
+}
+
+async fn unsubscribe(
+    Path(symbol): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    update(&symbol, query.get("feeds"), false)
+}
+
+fn update(symbol: &str, feeds: Option<&String>, subscribe: bool) -> Response {
+    if !crystal::running() {
+        return reply(StatusCode::CONFLICT, "no Crystal session is running");
+    }
+    let feeds = match feeds {
+        Some(feeds) => match subscriptions::parse_feeds(feeds) {
+            Ok(feeds) => feeds,
+            Err(e) => return reply(StatusCode::BAD_REQUEST, &e),
+        },
+        None => ALL_FEEDS.to_vec(),
+    };
+    let mut changed = Vec::new();
+    for feed in feeds {
+        let result = if subscribe {
+            crystal::subscribe(symbol, feed)
+        } else {
+            crystal::unsubscribe(symbol, feed)
+        };
+        match result {
+            Ok(true) => changed.push(feed.to_string()),
+            Ok(false) => {}
+            Err(e) => return reply(StatusCode::BAD_REQUEST, &e.to_string()),
+        }
+    }
+    Json(json!({
+        "symbol": subscriptions::normalize(symbol),
+        "changed": changed,
+    }))
+    .into_response()
+}
@@ -2,19 +2,79 @@ use chrono::{Datelike, Utc};
 use sentry::Level;
 use std::fs::create_dir_all;
 use std::path::Path;
+use std::sync::Mutex;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 
 use crate::helpers::config::vault_url;
+use crate::helpers::config::{connections, credential_sets};
 use crate::helpers::config::MARKETDATA_ADDRESS;
 
 use crate::core::crystal;
+use crate::core::crystal_params::{Credentials, CrystalParams};
+use crate::core::endpoints;
+use crate::core::recovery;
 use crate::helpers::assets;
 use crate::helpers::config::MARKETDATA_PW;
 use crate::helpers::config::MARKETDATA_UN;
+use crate::helpers::errors::report_error;
 use crate::helpers::quotes;
 use crate::helpers::unzip;
 use crate::helpers::vault;
 
+// * Set from the start of run() until the session ends, even when run() panics.
+// * Cancelling the token stops the capture while it prepares or once the session runs
+static RUNNING: Mutex<Option<CancellationToken>> = Mutex::new(None);
+
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        *RUNNING.lock().unwrap() = None;
+    }
+}
+
+pub fn running() -> bool {
+    RUNNING.lock().unwrap().is_some()
+}
+
+// * A second run would set aside the files of the running session as leftover capture
 pub async fn run() {
+    let cancel = {
+        let mut running = RUNNING.lock().unwrap();
+        if running.is_some() {
+            warn!("Capture is already running, start ignored");
+            return;
+        }
+        running.insert(CancellationToken::new()).clone()
+    };
+    let _running = Running;
+    tokio::select! {
+        _ = cancel.cancelled() => info!("Capture stopped before the session started"),
+        params = prepare() => {
+            if let Some(params) = params {
+                capture(params, cancel).await;
+            }
+        }
+    }
+}
+
+// * Ends the capture, even one still preparing, then uploads content/
+pub async fn stop() {
+    info!("Stop signal");
+    let cancel = RUNNING.lock().unwrap().clone();
+    if let Some(cancel) = cancel {
+        cancel.cancel();
+        while running() {
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+    crystal::upload().await;
+    sentry::capture_message("CMDC has finished", Level::Info);
+}
+
+async fn prepare() -> Option<CrystalParams> {
     sentry::capture_message("CMDC is running", Level::Info);
     let current_year = Utc::now().year();
     let quotes_url = format!(
@@ -27,94 +87,92 @@ pub async fn run() {
     let unzipped_quotes = resources_dir.join(format!("COTAHIST_A{}.TXT", current_year));
     let assets_file = resources_dir.join(format!("assets-{}.txt", current_year));
 
-    if let Err(e) = create_dir_all(&resources_dir) {
+    if let Err(e) = create_dir_all(resources_dir) {
         let error_message = format!(
             "Error: create resources directory {} - {}",
             resources_dir.display(),
             e
         );
-        sentry::capture_error(&Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            error_message.clone(),
-        )));
-        eprintln!("{}", error_message);
-        return;
+        report_error(&error_message);
+        return None;
     }
 
-    if let Err(e) = create_dir_all(&content_dir) {
+    if let Err(e) = create_dir_all(content_dir) {
         let error_message = format!(
             "Error: create content directory {} - {}",
             resources_dir.display(),
             e
         );
-        sentry::capture_error(&Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            error_message.clone(),
-        )));
-        return;
+        sentry::capture_error(&std::io::Error::other(error_message));
+        return None;
     }
 
+    // * Leftover capture from a crashed session is moved out before new output goes to content/,
+    // * its upload runs alongside the capture
+    recovery::recover();
+    recovery::mark_session();
+
     // TODO: FIX EDGE CASE: xxxx-01-01
     if quotes::download(&quotes_url, &quotes_path).await.is_err() {
-        sentry::capture_error(&Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Error: download quotes",
-        )));
-        return;
+        sentry::capture_error(&std::io::Error::other("Error: download quotes"));
+        return None;
     }
 
-    unzip::unzip_path(&quotes_path, &resources_dir);
+    unzip::unzip_path(&quotes_path, resources_dir);
 
     if let Err(e) = unzip::process_assets(&unzipped_quotes, &assets_file) {
         let error_message = format!("Error: {}", e);
-        sentry::capture_error(&Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            error_message.clone(),
-        )));
-        eprintln!("{}", error_message);
-        return;
+        report_error(&error_message);
+        return None;
     }
 
-    println!("Requesting secrets...");
+    info!("Requesting secrets");
     let mkt_data_address = vault::get_secret(MARKETDATA_ADDRESS, &vault_url())
         .await
         .unwrap();
-    let mkt_data_password = vault::get_secret(MARKETDATA_UN, &vault_url())
-        .await
-        .unwrap()
-        + "\n";
-    let mkt_data_username = vault::get_secret(MARKETDATA_PW, &vault_url())
-        .await
-        .unwrap()
-        + "\n";
-    println!("Secrets received");
+    let mut credentials = Vec::with_capacity(credential_sets());
+    for set in 0..credential_sets() {
+        let (username_secret, password_secret) = if set == 0 {
+            (MARKETDATA_UN.to_string(), MARKETDATA_PW.to_string())
+        } else {
+            (
+                format!("{}-{}", MARKETDATA_UN, set),
+                format!("{}-{}", MARKETDATA_PW, set),
+            )
+        };
+        let username = vault::get_secret(&username_secret, &vault_url())
+            .await
+            .unwrap()
+            + "\n";
+        let password = vault::get_secret(&password_secret, &vault_url())
+            .await
+            .unwrap()
+            + "\n";
+        credentials.push(Credentials { username, password });
+    }
+    info!("Secrets received");
 
     match assets::read_asset_names(assets_file.to_str().unwrap()) {
         Ok(asset_names) => {
-            println!("Unique assets: {}", asset_names.len());
-            if let Err(e) = crystal::start(
-                asset_names,
-                mkt_data_address,
-                mkt_data_password,
-                mkt_data_username,
-            )
-            .await
-            {
-                let error_message = format!("Crystal error: {}", e);
-                sentry::capture_error(&Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    error_message.clone(),
-                )));
-                eprintln!("{}", error_message);
-            }
+            info!(assets = asset_names.len(), "Unique assets");
+            Some(CrystalParams {
+                assets: asset_names,
+                mkt_data_addresses: endpoints::parse_addresses(&mkt_data_address),
+                credentials,
+                connections: connections(),
+            })
         }
         Err(e) => {
             let error_message = format!("Error: read asset names - {}", e);
-            sentry::capture_error(&Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                error_message.clone(),
-            )));
-            eprintln!("{}", error_message);
+            report_error(&error_message);
+            None
         }
     }
 }
+
+async fn capture(params: CrystalParams, cancel: CancellationToken) {
+    if let Err(e) = crystal::start(params, cancel).await {
+        let error_message = format!("Crystal error: {}", e);
+        report_error(&error_message);
+    }
+}
@@ -0,0 +1,254 @@
+use super::parse::{invalid, message_body, next_field, parse_price, parse_quantity, ParseError};
+
+// * Format:
+// * B:<symbol>:A:<position>:<side>:<price>:<quantity>:<broker>:<datetime>:<order id>:<order type>!
+// * B:<symbol>:U:<new position>:<old position>:<side>:<price>:<quantity>:<broker>:<datetime>:<order id>:<order type>!
+// * B:<symbol>:D:<delete type>:<side>:<position>!
+// * B:<symbol>:E!
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookMessage {
+    pub symbol: String,
+    pub event: BookEvent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookEvent {
+    Add {
+        position: usize,
+        order: Order,
+    },
+    Update {
+        position: usize,
+        old_position: usize,
+        order: Order,
+    },
+    Delete {
+        kind: DeleteKind,
+        side: Side,
+        position: usize,
+    },
+    // * End of the initial book snapshot
+    Snapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteKind {
+    // * 1 - the order at the position
+    Single,
+    // * 2 - every order from the top of the book down to the position
+    UpTo,
+    // * 3 - every order on the side (cancel)
+    All,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Order {
+    pub side: Side,
+    pub price: f64,
+    pub quantity: u64,
+    pub broker: String,
+    pub time: String,
+    pub order_id: String,
+    pub order_type: String,
+}
+
+// * Returns None when the line is not a BQT book message
+pub fn parse(line: &[u8]) -> Option<Result<BookMessage, ParseError>> {
+    message_body(line, "B").map(|body| body.and_then(parse_body))
+}
+
+fn parse_body(body: &str) -> Result<BookMessage, ParseError> {
+    let mut fields = body.split(':');
+    let symbol = next_field(&mut fields, "symbol")?;
+    if symbol.is_empty() {
+        return Err(ParseError::Truncated("symbol"));
+    }
+    let event = match next_field(&mut fields, "event")? {
+        "A" => {
+            let position = parse_position(next_field(&mut fields, "position")?)?;
+            BookEvent::Add {
+                position,
+                order: parse_order(&mut fields)?,
+            }
+        }
+        "U" => {
+            let position = parse_position(next_field(&mut fields, "new position")?)?;
+            let old_position = parse_position(next_field(&mut fields, "old position")?)?;
+            BookEvent::Update {
+                position,
+                old_position,
+                order: parse_order(&mut fields)?,
+            }
+        }
+        "D" => {
+            let kind = match next_field(&mut fields, "delete type")? {
+                "1" => DeleteKind::Single,
+                "2" => DeleteKind::UpTo,
+                "3" => DeleteKind::All,
+                other => return Err(invalid("delete type", other)),
+            };
+            let side = parse_side(next_field(&mut fields, "side")?)?;
+            let position = match fields.next() {
+                Some(value) if !value.is_empty() => parse_position(value)?,
+                _ if kind == DeleteKind::All => 0,
+                _ => return Err(ParseError::Truncated("position")),
+            };
+            BookEvent::Delete {
+                kind,
+                side,
+                position,
+            }
+        }
+        "E" => BookEvent::Snapshot,
+        other => return Err(invalid("book event", other)),
+    };
+
+    Ok(BookMessage {
+        symbol: symbol.to_string(),
+        event,
+    })
+}
+
+fn parse_order<'a>(fields: &mut impl Iterator<Item = &'a str>) -> Result<Order, ParseError> {
+    Ok(Order {
+        side: parse_side(next_field(fields, "side")?)?,
+        price: parse_price("price", next_field(fields, "price")?)?,
+        quantity: parse_quantity("quantity", next_field(fields, "quantity")?)?,
+        broker: next_field(fields, "broker")?.to_string(),
+        time: next_field(fields, "datetime")?.to_string(),
+        order_id: fields.next().unwrap_or_default().to_string(),
+        order_type: fields.next().unwrap_or_default().to_string(),
+    })
+}
+
+fn parse_position(value: &str) -> Result<usize, ParseError> {
+    value
+        .parse::<usize>()
+        .map_err(|_| invalid("position", value))
+}
+
+// * A - buy (compra), V - sell (venda)
+fn parse_side(value: &str) -> Result<Side, ParseError> {
+    match value {
+        "A" => Ok(Side::Bid),
+        "V" => Ok(Side::Ask),
+        other => Err(invalid("side", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(side: Side, price: f64, quantity: u64) -> Order {
+        Order {
+            side,
+            price,
+            quantity,
+            broker: "308".to_string(),
+            time: "20240502103015".to_string(),
+            order_id: "123456".to_string(),
+            order_type: "1".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_add_and_update() {
+        let add = parse(b"B:PETR4:A:0:A:38.49:500:308:20240502103015:123456:1!\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            add,
+            BookMessage {
+                symbol: "PETR4".to_string(),
+                event: BookEvent::Add {
+                    position: 0,
+                    order: order(Side::Bid, 38.49, 500),
+                },
+            }
+        );
+
+        let update = parse(b"B:PETR4:U:1:3:V:38.52:200:308:20240502103015:123456:1!")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            update.event,
+            BookEvent::Update {
+                position: 1,
+                old_position: 3,
+                order: order(Side::Ask, 38.52, 200),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_delete_and_snapshot_end() {
+        let delete = |line: &[u8]| parse(line).unwrap().unwrap().event;
+        assert_eq!(
+            delete(b"B:PETR4:D:1:A:2!"),
+            BookEvent::Delete {
+                kind: DeleteKind::Single,
+                side: Side::Bid,
+                position: 2,
+            }
+        );
+        assert_eq!(
+            delete(b"B:PETR4:D:2:V:4!"),
+            BookEvent::Delete {
+                kind: DeleteKind::UpTo,
+                side: Side::Ask,
+                position: 4,
+            }
+        );
+        assert_eq!(
+            delete(b"B:PETR4:D:3:V!"),
+            BookEvent::Delete {
+                kind: DeleteKind::All,
+                side: Side::Ask,
+                position: 0,
+            }
+        );
+        assert_eq!(delete(b"B:PETR4:E!"), BookEvent::Snapshot);
+    }
+
+    #[test]
+    fn ignores_unknown_trailing_fields() {
+        let message = parse(b"B:PETR4:A:0:A:38.49:500:308:20240502103015:123456:1:X:99!")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            message.event,
+            BookEvent::Add {
+                position: 0,
+                order: order(Side::Bid, 38.49, 500),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(parse(b"T:PETR4:103015:2:38.50!").is_none());
+        assert_eq!(
+            parse(b"B:PETR4:X!").unwrap(),
+            Err(invalid("book event", "X"))
+        );
+        assert_eq!(
+            parse(b"B:PETR4:D:4:A:0!").unwrap(),
+            Err(invalid("delete type", "4"))
+        );
+        assert_eq!(
+            parse(b"B:PETR4:D:1:A!").unwrap(),
+            Err(ParseError::Truncated("position"))
+        );
+        assert_eq!(
+            parse(b"B:PETR4:A:0:C:38.49:500:308:20240502103015!").unwrap(),
+            Err(invalid("side", "C"))
+        );
+    }
+}
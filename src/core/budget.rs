@@ -0,0 +1,73 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::stats::CaptureStats;
+use crate::helpers::metrics::METRICS;
+
+// * Bytes of capture data allowed in memory between the connections and the disk
+#[derive(Clone)]
+pub struct MemoryBudget {
+    semaphore: Arc<Semaphore>,
+    total: usize,
+    stats: Arc<CaptureStats>,
+}
+
+// * Held by a batch or event until it is written, gives the bytes back when dropped
+pub struct Reservation {
+    _permit: OwnedSemaphorePermit,
+    bytes: usize,
+    stats: Arc<CaptureStats>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.stats
+            .bytes_buffered
+            .fetch_sub(self.bytes, Ordering::SeqCst);
+        METRICS.buffered_bytes.sub(self.bytes as i64);
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(total: usize, stats: Arc<CaptureStats>) -> MemoryBudget {
+        let total = total.clamp(1, Semaphore::MAX_PERMITS);
+        MemoryBudget {
+            semaphore: Arc::new(Semaphore::new(total)),
+            total,
+            stats,
+        }
+    }
+
+    // * Anything larger than the whole budget takes all of it
+    fn permits(&self, bytes: usize) -> u32 {
+        bytes.clamp(1, self.total).min(u32::MAX as usize) as u32
+    }
+
+    fn reservation(&self, permit: OwnedSemaphorePermit) -> Reservation {
+        let bytes = permit.num_permits();
+        self.stats.bytes_buffered.fetch_add(bytes, Ordering::SeqCst);
+        METRICS.buffered_bytes.add(bytes as i64);
+        Reservation {
+            _permit: permit,
+            bytes,
+            stats: Arc::clone(&self.stats),
+        }
+    }
+
+    pub fn try_reserve(&self, bytes: usize) -> Option<Reservation> {
+        let permit = Arc::clone(&self.semaphore)
+            .try_acquire_many_owned(self.permits(bytes))
+            .ok()?;
+        Some(self.reservation(permit))
+    }
+
+    // * Waits for writers to give bytes back, the semaphore is never closed
+    pub async fn reserve(&self, bytes: usize) -> Reservation {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_many_owned(self.permits(bytes))
+            .await
+            .expect("memory budget closed");
+        self.reservation(permit)
+    }
+}
@@ -0,0 +1,797 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use super::compression::{self, Compression, FrameWriter};
+
+pub const TIMESTAMP_FORMAT: &str = "%H:%M:%S%.3f";
+const TIMESTAMP_LEN: usize = 12;
+const FULL_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.9fZ";
+const FULL_TIMESTAMP_LEN: usize = 30;
+const RUN_BUFFER_SIZE: usize = 8192;
+const WRITER_PREFIX: &str = "crystal-md-";
+const SESSION_PREFIX: &str = "crystal-md-session-";
+
+// * Segment merges run in the background while the session merge runs, every reader
+// * decompresses into its own temporary files
+static READERS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampFormat {
+    // * Arrival time of day in the host time zone without a sequence number, as read by older
+    // * consumers. Sessions are merged by time, lines from the same millisecond may swap
+    Legacy,
+    // * The legacy time after the session sequence number
+    Sequence,
+    // * UTC arrival with nanoseconds, offset from the session start and exchange time
+    Full,
+}
+
+impl TimestampFormat {
+    // * Format: full | sequence | legacy
+    pub fn parse(value: &str) -> Result<TimestampFormat, String> {
+        match value.trim().to_lowercase().as_str() {
+            "legacy" => Ok(TimestampFormat::Legacy),
+            "sequence" => Ok(TimestampFormat::Sequence),
+            "" | "full" => Ok(TimestampFormat::Full),
+            value => Err(format!("Error: invalid CHITA_TIMESTAMP_FORMAT {}", value)),
+        }
+    }
+}
+
+// * When a line arrived, taken as it is read from the socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub received: DateTime<Utc>,
+    // * Monotonic clock since the session started, unaffected by clock adjustments
+    pub monotonic: Duration,
+    // * Event time sent by the exchange, for quotes and trades
+    pub exchange: Option<NaiveTime>,
+}
+
+// * Format: HH:MM:SS.mmm <crystal line>
+// * Format: <sequence> HH:MM:SS.mmm <crystal line>
+// * Format: <sequence> <YYYY-MM-DDTHH:MM:SS.nnnnnnnnnZ> +<seconds>.<nanoseconds> <exchange HH:MM:SS.mmm | -> <crystal line>
+pub fn format_line(seq: u64, stamp: &Stamp, line: &[u8], format: TimestampFormat) -> String {
+    match format {
+        TimestampFormat::Legacy => format!(
+            "{} {}",
+            stamp
+                .received
+                .with_timezone(&Local)
+                .format(TIMESTAMP_FORMAT),
+            String::from_utf8_lossy(line)
+        ),
+        TimestampFormat::Sequence => format!(
+            "{} {} {}",
+            seq,
+            stamp
+                .received
+                .with_timezone(&Local)
+                .format(TIMESTAMP_FORMAT),
+            String::from_utf8_lossy(line)
+        ),
+        TimestampFormat::Full => format!(
+            "{} {} +{}.{:09} {} {}",
+            seq,
+            stamp.received.format(FULL_TIMESTAMP_FORMAT),
+            stamp.monotonic.as_secs(),
+            stamp.monotonic.subsec_nanos(),
+            stamp.exchange.map_or_else(
+                || "-".to_string(),
+                |time| time.format(TIMESTAMP_FORMAT).to_string()
+            ),
+            String::from_utf8_lossy(line)
+        ),
+    }
+}
+
+// * Legacy captures have no <sequence> field.
+// * The time is local for the legacy and sequence formats and UTC for the full one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineHeader {
+    pub seq: Option<u64>,
+    pub time: NaiveTime,
+    // * Only the full format records the UTC date
+    pub date: Option<NaiveDate>,
+    pub body_start: usize,
+}
+
+pub fn parse_header(line: &[u8]) -> Option<LineHeader> {
+    let mut seq = None;
+    let mut start = 0;
+    let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits > 0 && line.get(digits) == Some(&b' ') {
+        seq = std::str::from_utf8(&line[..digits]).ok()?.parse().ok();
+        start = digits + 1;
+    }
+
+    if let Some((received, body_start)) = parse_full_timestamp(line, start) {
+        return Some(LineHeader {
+            seq,
+            time: received.time(),
+            date: Some(received.date()),
+            body_start,
+        });
+    }
+
+    let end = start + TIMESTAMP_LEN;
+    if line.get(end) != Some(&b' ') {
+        return None;
+    }
+    let timestamp = std::str::from_utf8(&line[start..end]).ok()?;
+    let time = NaiveTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+    Some(LineHeader {
+        seq,
+        time,
+        date: None,
+        body_start: end + 1,
+    })
+}
+
+// * The UTC time followed by the monotonic offset and the exchange time, which are skipped
+fn parse_full_timestamp(line: &[u8], start: usize) -> Option<(NaiveDateTime, usize)> {
+    let end = start + FULL_TIMESTAMP_LEN;
+    if line.get(end) != Some(&b' ') || line.get(end + 1) != Some(&b'+') {
+        return None;
+    }
+    let timestamp = std::str::from_utf8(&line[start..end]).ok()?;
+    let received = NaiveDateTime::parse_from_str(timestamp, FULL_TIMESTAMP_FORMAT).ok()?;
+    let mut body_start = end + 1;
+    for _ in 0..2 {
+        body_start += line[body_start..].iter().position(|&b| b == b' ')? + 1;
+    }
+    Some((received, body_start))
+}
+
+// * One line of a capture file as it was written
+#[derive(Debug, Clone)]
+pub struct CapturedLine {
+    pub header: LineHeader,
+    pub raw: Vec<u8>,
+}
+
+impl CapturedLine {
+    // * The original Crystal line, line end included
+    pub fn line(&self) -> &[u8] {
+        &self.raw[self.header.body_start..]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    // * Exact arrival order inside one session
+    Sequence,
+    // * Recorded time, for days with several sessions or captures without sequence numbers
+    Time,
+}
+
+impl Order {
+    fn key(&self, header: &LineHeader) -> (NaiveTime, u64) {
+        match self {
+            Order::Sequence => (NaiveTime::MIN, header.seq.unwrap_or(0)),
+            Order::Time => (header.time, header.seq.unwrap_or(0)),
+        }
+    }
+}
+
+// * content/crystal-md-<i>.txt, compressed or not, ordered by writer index
+pub fn writer_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<(usize, PathBuf)> = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(compression::plain_name)
+            .and_then(|name| name.strip_prefix(WRITER_PREFIX))
+            .and_then(|name| name.strip_suffix(".txt"))
+            .and_then(|index| index.parse().ok());
+        if let Some(index) = index {
+            files.push((index, path));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+// * Merged session files, or symbol and bucket partitions when no session was merged, followed
+// * by writer files left over from an unfinished session. Partitions hold the same lines as the
+// * session file merged from them. Segment folders are walked the same way
+pub fn capture_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let writers = writer_files(dir)?;
+    let mut sessions = Vec::new();
+    let mut partitions = Vec::new();
+    let mut folders = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            folders.push(path);
+            continue;
+        }
+        let Some(name) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(compression::plain_name)
+            .filter(|name| name.starts_with(WRITER_PREFIX) && name.ends_with(".txt"))
+        else {
+            continue;
+        };
+        if name.starts_with(SESSION_PREFIX) {
+            sessions.push(path);
+        } else if !writers.contains(&path) {
+            partitions.push(path);
+        }
+    }
+    let mut files = if sessions.is_empty() {
+        partitions
+    } else {
+        sessions
+    };
+    files.sort();
+    files.extend(writers);
+    folders.sort();
+    for folder in folders {
+        files.extend(capture_files(&folder)?);
+    }
+    Ok(files)
+}
+
+pub fn session_file(dir: &Path, started: &DateTime<Local>, compression: Compression) -> PathBuf {
+    dir.join(format!(
+        "{}{}.txt{}",
+        SESSION_PREFIX,
+        started.format("%H%M%S"),
+        compression.extension()
+    ))
+}
+
+// * Symbol and bucket partitions, crystal-md-<symbol | bucket | other>.txt
+fn partition_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let writers = writer_files(dir)?;
+    let mut files = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let is_partition = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(compression::plain_name)
+            .is_some_and(|name| {
+                name.starts_with(WRITER_PREFIX)
+                    && name.ends_with(".txt")
+                    && !name.starts_with(SESSION_PREFIX)
+            });
+        if is_partition && !writers.contains(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// * Writes the capture of one session as a single stream in sequence order.
+// * Writer files are removed once the merged file is on disk. Symbol and bucket partitions get
+// * lines from every connection, so each one is rewritten in order first and then kept
+pub fn merge_session(dir: &Path, output: &Path, compression: Compression) -> io::Result<usize> {
+    let writers = writer_files(dir)?;
+    let files = if writers.is_empty() {
+        let partitions = partition_files(dir)?;
+        for partition in &partitions {
+            sort_file(partition, compression)?;
+        }
+        partitions
+    } else {
+        writers.clone()
+    };
+    if files.is_empty() {
+        return Ok(0);
+    }
+
+    let (lines, gaps) = write_ordered(open_ordered(&files)?, output, compression)?;
+    if gaps > 0 {
+        warn!(gaps, output = %output.display(), "Sequence numbers missing");
+    }
+    for file in writers {
+        remove_file(file)?;
+    }
+    Ok(lines)
+}
+
+// * Rewrites a capture file in sequence order, files already in order are left alone
+fn sort_file(path: &Path, compression: Compression) -> io::Result<()> {
+    let reader = open_ordered(&[path.to_path_buf()])?;
+    if reader.runs.len() <= 1 {
+        return Ok(());
+    }
+    let partial = PathBuf::from(format!("{}.partial", path.display()));
+    write_ordered(reader, &partial, compression)?;
+    rename(&partial, path)
+}
+
+// * Sequence order, or time order for legacy captures that have no sequence numbers
+fn open_ordered(files: &[PathBuf]) -> io::Result<CaptureReader> {
+    let reader = CaptureReader::open(files, Order::Sequence)?;
+    if reader.sequenced() {
+        return Ok(reader);
+    }
+    // * Dropped first so its temporary files are removed before decompressing again
+    drop(reader);
+    CaptureReader::open(files, Order::Time)
+}
+
+// * Returns the lines written and how many sequence numbers are missing between them
+fn write_ordered(
+    reader: CaptureReader,
+    output: &Path,
+    compression: Compression,
+) -> io::Result<(usize, u64)> {
+    let mut writer = FrameWriter::new(BufWriter::new(File::create(output)?), compression);
+    let mut lines = 0;
+    let mut gaps = 0;
+    let mut expected: Option<u64> = None;
+    for line in reader {
+        let line = line?;
+        if let (Some(seq), Some(expected)) = (line.header.seq, expected) {
+            if seq > expected {
+                gaps += seq - expected;
+            }
+        }
+        expected = line.header.seq.map(|seq| seq + 1);
+        writer.write_all(&line.raw)?;
+        lines += 1;
+    }
+    writer.flush()?;
+    writer.get_ref().get_ref().sync_all()?;
+    Ok((lines, gaps))
+}
+
+// * A stretch of a capture file where the order key never goes back.
+// * Batches from every connection share the writers, so a file is only ordered within a run
+struct Run {
+    file: usize,
+    offset: u64,
+    end: u64,
+    buffer: Vec<u8>,
+    pos: usize,
+    last: (NaiveTime, Option<u64>),
+}
+
+impl Run {
+    fn next_line(&mut self, file: &File) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(n) = self.buffer[self.pos..].iter().position(|&b| b == b'\n') {
+                let line = self.buffer[self.pos..self.pos + n + 1].to_vec();
+                self.pos += n + 1;
+                return Ok(Some(line));
+            }
+            if self.offset >= self.end {
+                if self.pos < self.buffer.len() {
+                    let line = self.buffer[self.pos..].to_vec();
+                    self.pos = self.buffer.len();
+                    return Ok(Some(line));
+                }
+                return Ok(None);
+            }
+
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+            let start = self.buffer.len();
+            let len = RUN_BUFFER_SIZE.min((self.end - self.offset) as usize);
+            self.buffer.resize(start + len, 0);
+            let n = file.read_at(&mut self.buffer[start..], self.offset)?;
+            self.buffer.truncate(start + n);
+            if n == 0 {
+                self.end = self.offset;
+            }
+            self.offset += n as u64;
+        }
+    }
+
+    fn next(&mut self, file: &File) -> io::Result<Option<CapturedLine>> {
+        let Some(raw) = self.next_line(file)? else {
+            return Ok(None);
+        };
+        let header = resolve_header(&raw, &mut self.last);
+        Ok(Some(CapturedLine { header, raw }))
+    }
+}
+
+// * Lines without a header keep the time and sequence of the line before them
+fn resolve_header(raw: &[u8], last: &mut (NaiveTime, Option<u64>)) -> LineHeader {
+    match parse_header(raw) {
+        Some(header) => {
+            *last = (header.time, header.seq);
+            header
+        }
+        None => LineHeader {
+            seq: last.1,
+            time: last.0,
+            date: None,
+            body_start: 0,
+        },
+    }
+}
+
+// * Merges the runs of every capture file back into the order the lines were received.
+// * Compressed files are decompressed into the temporary directory first, runs need random access
+pub struct CaptureReader {
+    order: Order,
+    files: Vec<File>,
+    temporary: Vec<PathBuf>,
+    runs: Vec<Run>,
+    heads: Vec<Option<CapturedLine>>,
+    heap: BinaryHeap<Reverse<((NaiveTime, u64), usize)>>,
+    // * The last line returned that had a sequence number
+    last: Option<Vec<u8>>,
+}
+
+impl CaptureReader {
+    pub fn open(paths: &[PathBuf], order: Order) -> io::Result<CaptureReader> {
+        // * Built up front so the temporary files are removed if a file fails to open
+        let mut reader = CaptureReader {
+            order,
+            files: Vec::with_capacity(paths.len()),
+            temporary: Vec::new(),
+            runs: Vec::new(),
+            heads: Vec::new(),
+            heap: BinaryHeap::new(),
+            last: None,
+        };
+        let id = READERS.fetch_add(1, Ordering::SeqCst);
+        for (index, path) in paths.iter().enumerate() {
+            let path = if compression::is_compressed(path) {
+                let plain = std::env::temp_dir().join(format!(
+                    "chita-{}-{}-{}.txt",
+                    process::id(),
+                    id,
+                    index
+                ));
+                reader.temporary.push(plain.clone());
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&plain)?;
+                compression::decompress(path, &mut file)?;
+                plain
+            } else {
+                path.clone()
+            };
+            reader.runs.extend(scan_runs(index, &path, order)?);
+            reader.files.push(File::open(&path)?);
+        }
+        debug!(
+            files = reader.files.len(),
+            runs = reader.runs.len(),
+            "Capture ordered runs"
+        );
+
+        reader.heads = vec![None; reader.runs.len()];
+        for run in 0..reader.runs.len() {
+            reader.advance(run)?;
+        }
+        Ok(reader)
+    }
+
+    // * Whether the first line of every run has a sequence number
+    fn sequenced(&self) -> bool {
+        self.heads
+            .iter()
+            .flatten()
+            .all(|line| line.header.seq.is_some())
+    }
+
+    fn advance(&mut self, run: usize) -> io::Result<()> {
+        let file = &self.files[self.runs[run].file];
+        if let Some(line) = self.runs[run].next(file)? {
+            self.heap.push(Reverse((self.order.key(&line.header), run)));
+            self.heads[run] = Some(line);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CaptureReader {
+    fn drop(&mut self) {
+        for path in &self.temporary {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = io::Result<CapturedLine>;
+
+    // * A writer that fails spills its whole batch, lines it wrote before failing may be on disk
+    // * already and come back from the spool. Copies have the same sequence number and bytes,
+    // * so they end up next to each other and only the first is returned
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Reverse((_, run)) = self.heap.pop()?;
+            let line = self.heads[run].take()?;
+            if let Err(e) = self.advance(run) {
+                return Some(Err(e));
+            }
+            if line.header.seq.is_none() || line.header.body_start == 0 {
+                return Some(Ok(line));
+            }
+            if self.last.as_ref() == Some(&line.raw) {
+                continue;
+            }
+            self.last = Some(line.raw.clone());
+            return Some(Ok(line));
+        }
+    }
+}
+
+fn scan_runs(index: usize, path: &Path, order: Order) -> io::Result<Vec<Run>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut runs = Vec::new();
+    let mut raw = Vec::new();
+    let mut offset: u64 = 0;
+    let mut run_start: u64 = 0;
+    let mut last = (NaiveTime::MIN, None);
+    let mut previous = None;
+
+    loop {
+        raw.clear();
+        let n = reader.read_until(b'\n', &mut raw)?;
+        if n == 0 {
+            break;
+        }
+        let key = order.key(&resolve_header(&raw, &mut last));
+        if previous.is_some_and(|previous| key < previous) {
+            runs.push(new_run(index, run_start, offset));
+            run_start = offset;
+        }
+        previous = Some(key);
+        offset += n as u64;
+    }
+    if offset > run_start {
+        runs.push(new_run(index, run_start, offset));
+    }
+    Ok(runs)
+}
+
+fn new_run(file: usize, offset: u64, end: u64) -> Run {
+    Run {
+        file,
+        offset,
+        end,
+        buffer: Vec::with_capacity(RUN_BUFFER_SIZE),
+        pos: 0,
+        last: (NaiveTime::MIN, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+    use std::fs::{read_to_string, write};
+
+    fn time(h: u32, m: u32, s: u32, ms: u32) -> NaiveTime {
+        NaiveTime::from_hms_milli_opt(h, m, s, ms).unwrap()
+    }
+
+    #[test]
+    fn parses_the_timestamp_format() {
+        assert_eq!(TimestampFormat::parse(""), Ok(TimestampFormat::Full));
+        assert_eq!(
+            TimestampFormat::parse(" Sequence "),
+            Ok(TimestampFormat::Sequence)
+        );
+        assert_eq!(
+            TimestampFormat::parse("legacy"),
+            Ok(TimestampFormat::Legacy)
+        );
+        assert!(TimestampFormat::parse("nanos").is_err());
+    }
+
+    #[test]
+    fn parses_every_header_format() {
+        let header = parse_header(b"10:30:15.123 T:PETR4:103015!\n").unwrap();
+        assert_eq!(header.seq, None);
+        assert_eq!(header.time, time(10, 30, 15, 123));
+        assert_eq!(header.body_start, 13);
+
+        let header = parse_header(b"42 10:30:15.123 T:PETR4:103015!\n").unwrap();
+        assert_eq!(header.seq, Some(42));
+        assert_eq!(header.time, time(10, 30, 15, 123));
+        assert_eq!(header.body_start, 16);
+
+        let line = b"42 2024-05-02T13:30:15.123456789Z +5.000000001 - T:PETR4:103015!\n";
+        let header = parse_header(line).unwrap();
+        assert_eq!(header.seq, Some(42));
+        assert_eq!(header.date, NaiveDate::from_ymd_opt(2024, 5, 2));
+        assert_eq!(
+            header.time,
+            NaiveTime::from_hms_nano_opt(13, 30, 15, 123456789).unwrap()
+        );
+        assert_eq!(&line[header.body_start..], b"T:PETR4:103015!\n");
+
+        assert_eq!(parse_header(b"T:PETR4:103015!\n"), None);
+    }
+
+    #[test]
+    fn formats_lines_the_header_parser_reads() {
+        let stamp = Stamp {
+            received: DateTime::from_timestamp(1714656615, 123456789).unwrap(),
+            monotonic: Duration::new(5, 1),
+            exchange: Some(time(10, 30, 15, 0)),
+        };
+        let line = format_line(7, &stamp, b"T:PETR4:103015!\n", TimestampFormat::Full);
+        assert_eq!(
+            line,
+            "7 2024-05-02T13:30:15.123456789Z +5.000000001 10:30:15.000 T:PETR4:103015!\n"
+        );
+        for format in [
+            TimestampFormat::Legacy,
+            TimestampFormat::Sequence,
+            TimestampFormat::Full,
+        ] {
+            let line = format_line(7, &stamp, b"T:PETR4:103015!\n", format);
+            let header = parse_header(line.as_bytes()).unwrap();
+            let seq = (format != TimestampFormat::Legacy).then_some(7);
+            assert_eq!(header.seq, seq, "{:?}", format);
+            assert_eq!(&line[header.body_start..], "T:PETR4:103015!\n");
+        }
+    }
+
+    #[test]
+    fn merges_out_of_order_runs_by_sequence() {
+        let dir = TempDir::new("merge-writers");
+        // * Batches from two connections interleaved on each writer
+        write(
+            dir.join("crystal-md-0.txt"),
+            "1 10:30:15.001 a\n4 10:30:15.004 d\n2 10:30:15.002 b\n7 10:30:15.007 g\n",
+        )
+        .unwrap();
+        write(
+            dir.join("crystal-md-1.txt"),
+            "5 10:30:15.005 e\n3 10:30:15.003 c\n6 10:30:15.006 f\n",
+        )
+        .unwrap();
+        let output = dir.join("crystal-md-session-103015.txt");
+
+        assert_eq!(
+            merge_session(dir.path(), &output, Compression::None).unwrap(),
+            7
+        );
+        let merged = read_to_string(&output).unwrap();
+        let seqs: Vec<&str> = merged
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(seqs, ["1", "2", "3", "4", "5", "6", "7"]);
+        assert!(writer_files(dir.path()).unwrap().is_empty());
+        assert_eq!(capture_files(dir.path()).unwrap(), vec![output]);
+    }
+
+    #[test]
+    fn keeps_lines_without_a_header_after_their_line() {
+        let dir = TempDir::new("merge-continuation");
+        write(
+            dir.join("crystal-md-0.txt"),
+            "2 10:30:15.002 b\ncontinued b\n",
+        )
+        .unwrap();
+        write(dir.join("crystal-md-1.txt"), "1 10:30:15.001 a\n").unwrap();
+        let output = dir.join("crystal-md-session-103015.txt");
+
+        merge_session(dir.path(), &output, Compression::None).unwrap();
+        assert_eq!(
+            read_to_string(&output).unwrap(),
+            "1 10:30:15.001 a\n2 10:30:15.002 b\ncontinued b\n"
+        );
+    }
+
+    #[test]
+    fn sorts_partitions_and_keeps_them() {
+        let dir = TempDir::new("merge-partitions");
+        let petr4 = dir.join("crystal-md-symbol-petr4.txt");
+        write(
+            &petr4,
+            "1 10:30:15.001 a\n4 10:30:15.004 d\n2 10:30:15.002 b\n",
+        )
+        .unwrap();
+        write(
+            dir.join("crystal-md-symbol-vale3.txt"),
+            "3 10:30:15.003 c\n",
+        )
+        .unwrap();
+        let output = dir.join("crystal-md-session-103015.txt");
+
+        assert_eq!(
+            merge_session(dir.path(), &output, Compression::None).unwrap(),
+            4
+        );
+        assert_eq!(
+            read_to_string(&petr4).unwrap(),
+            "1 10:30:15.001 a\n2 10:30:15.002 b\n4 10:30:15.004 d\n"
+        );
+        assert_eq!(capture_files(dir.path()).unwrap(), vec![output]);
+    }
+
+    #[test]
+    fn skips_lines_spilled_twice() {
+        let dir = TempDir::new("merge-spilled");
+        // * Writer 0 wrote 2 and 3 before failing, its batch was spilled and written by writer 1
+        write(
+            dir.join("crystal-md-0.txt"),
+            "1 10:30:15.001 a\n2 10:30:15.002 b\n3 10:30:15.003 c\n",
+        )
+        .unwrap();
+        write(
+            dir.join("crystal-md-1.txt"),
+            "2 10:30:15.002 b\n3 10:30:15.003 c\n4 10:30:15.004 d\n",
+        )
+        .unwrap();
+        let output = dir.join("crystal-md-session-103015.txt");
+
+        assert_eq!(
+            merge_session(dir.path(), &output, Compression::None).unwrap(),
+            4
+        );
+        assert_eq!(
+            read_to_string(&output).unwrap(),
+            "1 10:30:15.001 a\n2 10:30:15.002 b\n3 10:30:15.003 c\n4 10:30:15.004 d\n"
+        );
+    }
+
+    #[test]
+    fn concurrent_readers_keep_their_own_temporary_files() {
+        let dir = TempDir::new("reader-temporary");
+        let compression = Compression::Zstd(3);
+        let capture = |name: &str, data: &[u8]| {
+            let path = dir.join(&format!("{}.txt{}", name, compression.extension()));
+            let mut writer = FrameWriter::new(File::create(&path).unwrap(), compression);
+            writer.write_all(data).unwrap();
+            writer.flush().unwrap();
+            vec![path]
+        };
+        // * Larger than the run buffer, so the first reader goes back to its file while reading
+        let lines: String = (1..=2000)
+            .map(|seq| format!("{} 10:30:15.001 T:PETR4:103015:2:38.50!\r\n", seq))
+            .collect();
+        let first = capture("crystal-md-0", lines.as_bytes());
+        let second = capture("crystal-md-1", b"7 10:40:00.001 x\n");
+
+        let reader = CaptureReader::open(&first, Order::Sequence).unwrap();
+        let other = CaptureReader::open(&second, Order::Sequence).unwrap();
+        let read: Vec<u8> = reader.flat_map(|line| line.unwrap().raw).collect();
+        assert_eq!(read, lines.as_bytes());
+        assert_eq!(other.count(), 1);
+    }
+
+    #[test]
+    fn merges_legacy_captures_by_time() {
+        let dir = TempDir::new("merge-legacy");
+        write(
+            dir.join("crystal-md-0.txt"),
+            "10:30:15.001 a\n10:30:15.004 d\n",
+        )
+        .unwrap();
+        write(
+            dir.join("crystal-md-1.txt"),
+            "10:30:15.002 b\n10:30:15.003 c\n",
+        )
+        .unwrap();
+        let output = dir.join("crystal-md-session-103015.txt");
+
+        merge_session(dir.path(), &output, Compression::None).unwrap();
+        assert_eq!(
+            read_to_string(&output).unwrap(),
+            "10:30:15.001 a\n10:30:15.002 b\n10:30:15.003 c\n10:30:15.004 d\n"
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tracing::warn;
+
+const DEFAULT_GZIP_LEVEL: u32 = 6;
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+const FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+// * Every flush becomes an independent gzip member or zstd frame, so a file stays
+// * readable up to its last flush even if the process dies while writing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip(u32),
+    Zstd(i32),
+}
+
+impl Compression {
+    // * Format: none | gzip[:<level 0-9>] | zstd[:<level 1-22>]
+    pub fn parse(value: &str) -> Result<Compression, String> {
+        let value = value.trim().to_lowercase();
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (value.as_str(), None),
+        };
+        let invalid = || format!("Error: invalid CHITA_COMPRESSION {}", value);
+        match (name, level) {
+            ("" | "none", None) => Ok(Compression::None),
+            ("gzip", None) => Ok(Compression::Gzip(DEFAULT_GZIP_LEVEL)),
+            ("gzip", Some(level)) => match level.parse() {
+                Ok(level) if level <= 9 => Ok(Compression::Gzip(level)),
+                _ => Err(invalid()),
+            },
+            ("zstd", None) => Ok(Compression::Zstd(DEFAULT_ZSTD_LEVEL)),
+            ("zstd", Some(level)) => match level.parse() {
+                Ok(level) if (1..=22).contains(&level) => Ok(Compression::Zstd(level)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+
+    fn from_path(path: &Path) -> Compression {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") => Compression::Gzip(DEFAULT_GZIP_LEVEL),
+            Some("zst") => Compression::Zstd(DEFAULT_ZSTD_LEVEL),
+            _ => Compression::None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip(_) => ".gz",
+            Compression::Zstd(_) => ".zst",
+        }
+    }
+
+    // * One complete frame holding the data
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip(level) => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(*level));
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd(level) => zstd::bulk::compress(data, *level),
+        }
+    }
+}
+
+pub fn is_compressed(path: &Path) -> bool {
+    Compression::from_path(path) != Compression::None
+}
+
+// * Capture file name without the .gz or .zst extension
+pub fn plain_name(name: &str) -> &str {
+    name.strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".zst"))
+        .unwrap_or(name)
+}
+
+// * Decompresses a capture file into the writer, up to the last complete line.
+// * A frame cut short by a crash ends the copy with an error message instead of failing
+pub fn decompress(path: &Path, output: &mut File) -> io::Result<u64> {
+    let file = BufReader::new(File::open(path)?);
+    let mut reader: Box<dyn Read> = match Compression::from_path(path) {
+        Compression::None => Box::new(file),
+        Compression::Gzip(_) => Box::new(MultiGzDecoder::new(file)),
+        Compression::Zstd(_) => Box::new(zstd::stream::read::Decoder::with_buffer(file)?),
+    };
+
+    let mut writer = BufWriter::new(&mut *output);
+    let mut buffer = vec![0; 64 * 1024];
+    let mut written: u64 = 0;
+    let mut complete: u64 = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!(
+                    path = %path.display(),
+                    bytes = complete,
+                    error = %e,
+                    "Compressed file is truncated"
+                );
+                break;
+            }
+        };
+        writer.write_all(&buffer[..n])?;
+        if let Some(end) = buffer[..n].iter().rposition(|&b| b == b'\n') {
+            complete = written + end as u64 + 1;
+        }
+        written += n as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+
+    // * Only a truncated frame leaves a partial line behind
+    if complete < written && is_compressed(path) {
+        output.set_len(complete)?;
+        written = complete;
+    }
+    output.seek(SeekFrom::Start(0))?;
+    Ok(written)
+}
+
+// * Buffers writes and emits one frame every FRAME_SIZE bytes and on flush
+pub struct FrameWriter<W: Write> {
+    inner: W,
+    compression: Compression,
+    frame: Vec<u8>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub fn new(inner: W, compression: Compression) -> FrameWriter<W> {
+        FrameWriter {
+            inner,
+            compression,
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        if !self.frame.is_empty() {
+            let frame = self.compression.compress(&self.frame)?;
+            self.inner.write_all(&frame)?;
+            self.frame.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.compression == Compression::None {
+            return self.inner.write(data);
+        }
+        self.frame.extend_from_slice(data);
+        if self.frame.len() >= FRAME_SIZE {
+            self.write_frame()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_frame()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+    use std::fs::OpenOptions;
+    use std::path::PathBuf;
+
+    const FIRST: &[u8] = b"1 10:30:15.001 T:PETR4:103015:2:38.50!\r\n2 10:30:15.002 B:PETR4:E!\r\n";
+    const SECOND: &[u8] = b"3 10:30:16.001 V:PETR4:A:103016000:38.51:308:120:100:1:0:A!\r\n";
+
+    // * Writes FIRST and SECOND as two frames
+    fn write_capture(dir: &TempDir, compression: Compression) -> PathBuf {
+        let path = dir.join(&format!("crystal-md-0.txt{}", compression.extension()));
+        let mut writer = FrameWriter::new(File::create(&path).unwrap(), compression);
+        writer.write_all(FIRST).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(SECOND).unwrap();
+        writer.flush().unwrap();
+        path
+    }
+
+    fn read_back(dir: &TempDir, path: &Path) -> Vec<u8> {
+        let mut output = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dir.join("plain.txt"))
+            .unwrap();
+        let written = decompress(path, &mut output).unwrap();
+        let mut data = Vec::new();
+        output.read_to_end(&mut data).unwrap();
+        assert_eq!(written, data.len() as u64);
+        data
+    }
+
+    #[test]
+    fn parses_the_setting() {
+        assert_eq!(Compression::parse(""), Ok(Compression::None));
+        assert_eq!(Compression::parse("GZIP"), Ok(Compression::Gzip(6)));
+        assert_eq!(Compression::parse("gzip:9"), Ok(Compression::Gzip(9)));
+        assert_eq!(Compression::parse("zstd:19"), Ok(Compression::Zstd(19)));
+        assert!(Compression::parse("gzip:10").is_err());
+        assert!(Compression::parse("zstd:0").is_err());
+        assert!(Compression::parse("lz4").is_err());
+    }
+
+    #[test]
+    fn names_follow_the_extension() {
+        assert_eq!(plain_name("crystal-md-0.txt.gz"), "crystal-md-0.txt");
+        assert_eq!(plain_name("crystal-md-0.txt.zst"), "crystal-md-0.txt");
+        assert_eq!(plain_name("crystal-md-0.txt"), "crystal-md-0.txt");
+        assert!(is_compressed(Path::new("content/crystal-md-0.txt.zst")));
+        assert!(!is_compressed(Path::new("content/crystal-md-0.txt")));
+    }
+
+    #[test]
+    fn reads_every_frame_back() {
+        let expected = [FIRST, SECOND].concat();
+        for (name, compression) in [
+            ("frames-none", Compression::None),
+            ("frames-gzip", Compression::Gzip(6)),
+            ("frames-zstd", Compression::Zstd(3)),
+        ] {
+            let dir = TempDir::new(name);
+            let path = write_capture(&dir, compression);
+            assert_eq!(read_back(&dir, &path), expected, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn recovers_up_to_a_truncated_frame() {
+        for (name, compression) in [
+            ("truncated-gzip", Compression::Gzip(6)),
+            ("truncated-zstd", Compression::Zstd(3)),
+        ] {
+            let dir = TempDir::new(name);
+            let path = write_capture(&dir, compression);
+            let first_frame = compression.compress(FIRST).unwrap().len() as u64;
+            let len = path.metadata().unwrap().len();
+            // * A crash in the middle of the second frame
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(first_frame + (len - first_frame) / 2).unwrap();
+
+            let data = read_back(&dir, &path);
+            assert!(data.starts_with(FIRST), "{:?}", compression);
+            assert!(
+                SECOND.starts_with(&data[FIRST.len()..]),
+                "{:?}",
+                compression
+            );
+            assert_eq!(data.last(), Some(&b'\n'), "{:?}", compression);
+        }
+    }
+}
@@ -1,247 +1,716 @@
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, Utc};
+use rand::Rng;
 use sentry::Level;
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
 use tokio::fs::OpenOptions;
-use tokio::io::BufWriter;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, WriteHalf};
 use tokio::sync::{mpsc, Mutex};
-use tokio::time::{interval, sleep, Duration};
-
-use super::crystal_params::CrystalParams;
+use tokio::time::{interval, sleep, sleep_until, timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use super::capture::{self, Stamp, TimestampFormat, TIMESTAMP_FORMAT};
+use super::compression::Compression;
+use super::crystal_params::{Credentials, CrystalParams};
+use super::endpoints::Endpoints;
+use super::events::MarketEvent;
 use super::futures;
-use crate::helpers::config::{vault_url, BLOB_ACCOUNT, BLOB_CONTAINER, BLOB_KEY};
+use super::handshake::{self, HandshakeError};
+use super::order_book::OrderBooks;
+use super::pipeline::LineProcessor;
+use super::segments;
+use super::stats::CaptureStats;
+use super::subscriptions::{
+    self, Feed, SubscriptionCommand, SubscriptionList, SubscriptionRequest, Subscriptions,
+};
+use super::transport::{BoxedStream, Transport};
+use super::watchdog::IdleWatchdog;
+use super::writer::{BatchWriter, OutputMode, WriterPool};
+use crate::helpers::config::{
+    compression, failback_interval, failover_threshold, output_mode, rotate_interval,
+    subscriptions_file, timestamp_format, vault_url, BLOB_ACCOUNT, BLOB_CONTAINER, BLOB_KEY,
+};
+use crate::helpers::errors::report_error;
+use crate::helpers::health::{self, SessionState};
+use crate::helpers::metrics::METRICS;
 use crate::helpers::storage;
 use crate::helpers::vault;
 
-static STOP_FLAG: AtomicBool = AtomicBool::new(false);
-
-const MAX_BUFFER_SIZE: usize = 1000000;
-const BATCH_SIZE: usize = 10000;
-const NUM_WRITERS: usize = 20;
-const FLUSH_INTERVAL: u64 = 300;
-const RETRY_INTERVAL: u64 = 5;
-const MAX_RETRIES: usize = 10;
-const RECONNECT_DELAY: u64 = 10;
-
-pub fn start(
-    assets: Vec<String>,
-    mkt_data_address: String,
-    mkt_data_username: String,
-    mkt_data_password: String,
-) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn std::error::Error>>> + Send>> {
-    Box::pin(start_async(CrystalParams {
-        assets,
-        mkt_data_address,
-        mkt_data_username,
-        mkt_data_password,
-    }))
+const CONTENT_DIR: &str = "content";
+const CONNECT_TIMEOUT: u64 = 30;
+const RECONNECT_BASE_DELAY: u64 = 1;
+const RECONNECT_MAX_DELAY: u64 = 60;
+const STABLE_CONNECTION: u64 = 60;
+pub const BOOK_SNAPSHOT_INTERVAL: u64 = 60;
+pub const BOOK_DEPTH: usize = 5;
+const SUBSCRIPTIONS_POLL: u64 = 5;
+
+struct Session {
+    id: u64,
+    // * Indexed by connection, see subscriptions::shard_of
+    subscriptions: Vec<Arc<StdMutex<Subscriptions>>>,
+    commands: Vec<mpsc::UnboundedSender<SubscriptionCommand>>,
+    stats: Arc<CaptureStats>,
+}
+
+static SESSION: StdMutex<Option<Session>> = StdMutex::new(None);
+static SESSION_ID: AtomicU64 = AtomicU64::new(0);
+// * Held while content/ is sent to the Blob Storage, so stop and upload never overlap
+static UPLOAD: Mutex<()> = Mutex::const_new(());
+
+// * State of one Crystal connection and the symbols assigned to it
+struct ShardContext {
+    index: usize,
+    addresses: Vec<String>,
+    transport: Transport,
+    credentials: Credentials,
+    processor: LineProcessor,
 }
 
-async fn start_async(params: CrystalParams) -> Result<(), Box<dyn std::error::Error>> {
-    STOP_FLAG.store(false, Ordering::SeqCst);
-
-    let params = Arc::new(params);
-
-    let server_host = params.mkt_data_address.clone();
-    let stream = match TcpStream::connect(server_host.clone()).await {
-        Ok(s) => s,
-        Err(e) => {
-            let error_message = format!("Error: connect to Crystal - {:?}", e);
-            sentry::capture_error(&Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                error_message.clone(),
-            )));
-            eprintln!("{}", error_message);
-            reconnect(params.clone()).await?;
-            return Ok(());
+enum ConnectionError {
+    AuthFailed(HandshakeError),
+    // * The endpoint could not be reached or did not complete the login
+    Unreachable(String),
+    Lost(String),
+}
+
+// * Runs until stop cancels the capture through the parent token or every connection gives up
+pub async fn start(params: CrystalParams, parent: CancellationToken) -> Result<(), Box<dyn Error>> {
+    if params.credentials.is_empty() {
+        return Err("Error: no Crystal credentials".into());
+    }
+    if params.mkt_data_addresses.is_empty() {
+        return Err("Error: no Crystal endpoints".into());
+    }
+
+    let transport = Transport::from_config()?;
+    info!(%transport, "Crystal transport");
+
+    let id = SESSION_ID.fetch_add(1, Ordering::SeqCst);
+    let cancel = parent.child_token();
+    let tracker = TaskTracker::new();
+    let stats = Arc::new(CaptureStats::default());
+
+    let connections = params.connections.max(1);
+    let mut symbols = vec![Vec::new(); connections];
+    for symbol in futures::get_futures()
+        .into_iter()
+        .chain(params.assets.iter().cloned())
+    {
+        symbols[subscriptions::shard_of(&symbol, connections)].push(symbol);
+    }
+
+    let mut shards = Vec::with_capacity(connections);
+    let mut commands = Vec::with_capacity(connections);
+    let mut commands_rx = Vec::with_capacity(connections);
+    for (index, symbols) in symbols.into_iter().enumerate() {
+        info!(
+            session = id,
+            connection = index,
+            symbols = symbols.len(),
+            "Crystal connection symbols"
+        );
+        let (tx, rx) = mpsc::unbounded_channel();
+        commands.push(tx);
+        commands_rx.push(rx);
+        shards.push(Arc::new(ShardContext {
+            index,
+            addresses: params.mkt_data_addresses.clone(),
+            transport: transport.clone(),
+            credentials: params.credentials[index % params.credentials.len()].clone(),
+            processor: LineProcessor {
+                subscriptions: Arc::new(StdMutex::new(Subscriptions::new(symbols))),
+                books: Arc::new(Mutex::new(OrderBooks::default())),
+                stats: Arc::clone(&stats),
+            },
+        }));
+    }
+
+    {
+        let mut session = SESSION.lock().unwrap();
+        if session.is_some() {
+            return Err("Error: a Crystal session is already running".into());
         }
+        *session = Some(Session {
+            id,
+            subscriptions: shards
+                .iter()
+                .map(|shard| Arc::clone(&shard.processor.subscriptions))
+                .collect(),
+            commands,
+            stats: Arc::clone(&stats),
+        });
+    }
+
+    health::session_started();
+    let session = tracker.spawn(
+        run_session(
+            shards,
+            commands_rx,
+            stats,
+            cancel.clone(),
+            tracker.clone(),
+            Local::now(),
+        )
+        .instrument(info_span!("session", session = id)),
+    );
+    let result = match session.await {
+        Ok(result) => result,
+        Err(e) => Err(format!("Error: session task - {:?}", e)),
     };
-    let stream = Arc::new(Mutex::new(stream));
 
-    let mut writers: Vec<Arc<Mutex<BufWriter<tokio::fs::File>>>> = Vec::new();
-    let mut txs: Vec<mpsc::Sender<Vec<(DateTime<Local>, Vec<u8>)>>> = Vec::new();
+    // * Segment uploads still in flight finish before the final upload of content/
+    cancel.cancel();
+    tracker.close();
+    tracker.wait().await;
+    health::session_ended();
+    let mut session = SESSION.lock().unwrap();
+    if session.as_ref().is_some_and(|session| session.id == id) {
+        *session = None;
+    }
+    result.map_err(Into::into)
+}
 
-    let message_count = Arc::new(AtomicUsize::new(0));
-    let total_lines_sent = Arc::new(AtomicUsize::new(0));
-    let message_count_report = Arc::clone(&message_count);
-    let total_lines_sent_report = Arc::clone(&total_lines_sent);
+pub fn subscribe(symbol: &str, feed: Feed) -> Result<bool, Box<dyn Error>> {
+    update_subscription(symbol, feed, true)
+}
 
-    for i in 0..NUM_WRITERS {
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(format!("content/crystal-md-{}.txt", i))
-            .await?;
-        let writer = Arc::new(Mutex::new(BufWriter::new(file)));
-        let (tx, mut rx) = mpsc::channel::<Vec<(DateTime<Local>, Vec<u8>)>>(MAX_BUFFER_SIZE);
-        txs.push(tx);
-
-        let writer_clone = Arc::clone(&writer);
-        let message_count_clone = Arc::clone(&message_count);
-
-        tokio::spawn(async move {
-            let mut flush_interval = interval(Duration::from_secs(FLUSH_INTERVAL));
-            loop {
-                if STOP_FLAG.load(Ordering::SeqCst) {
-                    println!("Stopping writer {}", i);
-                    break;
-                }
-                tokio::select! {
-                    Some(batch) = rx.recv() => {
-                        let mut writer = writer_clone.lock().await;
-                        for (timestamp, line) in &batch {
-                            let timestamp_str = timestamp.format("%H:%M:%S%.3f").to_string();
-                            let line_with_timestamp =
-                                format!("{} {}", timestamp_str, String::from_utf8_lossy(&line));
-                            let mut retries = 0;
-                            loop {
-                                if let Err(e) = writer.write_all(line_with_timestamp.as_bytes()).await {
-                                    println!("Error: write to file {} - {:?} at {:?}, retrying [{}/{}]", i, e, timestamp, retries + 1, MAX_RETRIES);
-                                    retries += 1;
-                                    if retries >= MAX_RETRIES {
-                                        println!("Error: max retries reached for writer {} - {:?} at {:?}", i, e, timestamp);
-                                        return;
-                                    }
-                                    sleep(Duration::from_secs(RETRY_INTERVAL)).await;
-                                } else {
-                                    break;
-                                }
-                            }
-                        }
-                        let mut retries = 0;
-                        loop {
-                            if let Err(e) = writer.flush().await {
-                                println!("Error: flush writer {} - {:?} at periodic flush, retrying [{}/{}]", i, e, retries + 1, MAX_RETRIES);
-                                retries += 1;
-                                if retries >= MAX_RETRIES {
-                                    println!("Error: max retries reached for flush on writer {} - {:?}", i, e);
-                                    return;
-                                }
-                                sleep(Duration::from_secs(RETRY_INTERVAL)).await;
-                            } else {
-                                break;
-                            }
-                        }
-                        message_count_clone.fetch_sub(batch.len(), Ordering::SeqCst);
-                    },
-                    _ = flush_interval.tick() => {
-                        let mut writer = writer_clone.lock().await;
-                        let mut retries = 0;
-                        loop {
-                            if let Err(e) = writer.flush().await {
-                                println!("Error: flush writer {} - {:?}, retrying [{}/{}]", i, e, retries + 1, MAX_RETRIES);
-                                retries += 1;
-                                if retries >= MAX_RETRIES {
-                                    println!("Error: max retries reached for flush on writer {} - {:?}", i, e);
-                                    return;
-                                }
-                                sleep(Duration::from_secs(RETRY_INTERVAL)).await;
-                            } else {
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
-        });
+pub fn unsubscribe(symbol: &str, feed: Feed) -> Result<bool, Box<dyn Error>> {
+    update_subscription(symbol, feed, false)
+}
+
+pub fn running() -> bool {
+    SESSION.lock().unwrap().is_some()
+}
+
+pub fn uploading() -> bool {
+    UPLOAD.try_lock().is_err()
+}
+
+pub fn stats() -> Option<Arc<CaptureStats>> {
+    let session = SESSION.lock().unwrap();
+    session.as_ref().map(|session| Arc::clone(&session.stats))
+}
+
+pub fn subscriptions() -> Option<SubscriptionList> {
+    let session = SESSION.lock().unwrap();
+    session.as_ref().map(|session| {
+        let mut list: SubscriptionList = session
+            .subscriptions
+            .iter()
+            .flat_map(|subscriptions| subscriptions.lock().unwrap().list())
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list
+    })
+}
 
-        writers.push(writer);
+// * The set is updated right away, the command reaches Crystal when a connection is up
+fn update_subscription(symbol: &str, feed: Feed, subscribe: bool) -> Result<bool, Box<dyn Error>> {
+    let symbol = subscriptions::normalize(symbol);
+    if symbol.is_empty() {
+        return Err("Error: empty symbol".into());
+    }
+    let session = SESSION.lock().unwrap();
+    let session = session
+        .as_ref()
+        .ok_or("Error: no Crystal session is running")?;
+
+    let shard = subscriptions::shard_of(&symbol, session.subscriptions.len());
+    let changed = if subscribe {
+        session.subscriptions[shard]
+            .lock()
+            .unwrap()
+            .insert(&symbol, feed)
+    } else {
+        session.subscriptions[shard]
+            .lock()
+            .unwrap()
+            .remove(&symbol, feed)
+    };
+    if changed {
+        let command = SubscriptionCommand {
+            symbol,
+            feed,
+            subscribe,
+        };
+        info!(
+            session = session.id,
+            symbol = %command.symbol,
+            feed = %command.feed,
+            subscribe,
+            "Subscription updated"
+        );
+        session.commands[shard].send(command)?;
     }
+    Ok(changed)
+}
 
-    let mut read_buffer = Vec::with_capacity(16384);
+async fn run_session(
+    shards: Vec<Arc<ShardContext>>,
+    commands: Vec<mpsc::UnboundedReceiver<SubscriptionCommand>>,
+    stats: Arc<CaptureStats>,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
+    started: DateTime<Local>,
+) -> Result<(), String> {
+    let mode = OutputMode::parse(&output_mode()).unwrap_or_else(|e| {
+        warn!("{}, using batch", e);
+        OutputMode::Batch
+    });
+    let compression = Compression::parse(&compression()).unwrap_or_else(|e| {
+        warn!("{}, writing uncompressed", e);
+        Compression::None
+    });
+    let format = TimestampFormat::parse(&timestamp_format()).unwrap_or_else(|e| {
+        warn!("{}, using full", e);
+        TimestampFormat::Full
+    });
+    let writers = TaskTracker::new();
+    let pool = WriterPool::start(&writers, Arc::clone(&stats), mode, compression, format);
 
-    let futures = futures::get_futures();
+    tracker.spawn(report_stats(stats, cancel.clone()).in_current_span());
+    let requests = subscriptions_file();
+    if !requests.is_empty() {
+        tracker.spawn(watch_requests(PathBuf::from(requests), cancel.clone()).in_current_span());
+    }
+    tracker.spawn(
+        snapshot_books(
+            shards
+                .iter()
+                .map(|shard| Arc::clone(&shard.processor.books))
+                .collect(),
+            cancel.clone(),
+        )
+        .in_current_span(),
+    );
+
+    let drain = tokio::spawn(pool.clone().run_drain(cancel.clone()).in_current_span());
+
+    let interval = rotate_interval();
+    let rotation = (interval > 0).then(|| {
+        tokio::spawn(
+            segments::run_rotation(
+                pool.clone(),
+                interval,
+                compression,
+                started,
+                cancel.clone(),
+                tracker.clone(),
+            )
+            .in_current_span(),
+        )
+    });
 
-    let mut subscriptions: Vec<String> = futures
-        .into_iter()
-        .chain(params.assets.clone().into_iter())
+    let supervisors: Vec<_> = shards
+        .iter()
+        .zip(commands)
+        .map(|(shard, commands)| {
+            tokio::spawn(
+                supervise(Arc::clone(shard), commands, pool.batcher(), cancel.clone())
+                    .instrument(info_span!("connection", connection = shard.index)),
+            )
+        })
         .collect();
 
-    let connected = Arc::new(Mutex::new(false));
-    let connected_clone = Arc::clone(&connected);
-    let stream_clone = Arc::clone(&stream);
+    let mut auth_failures = Vec::new();
+    for supervisor in supervisors {
+        match supervisor.await {
+            Ok(Some(e)) => auth_failures.push(e.to_string()),
+            Ok(None) => {}
+            Err(e) => error!(error = ?e, "Connection task failed"),
+        }
+    }
 
-    tokio::spawn({
-        let params = params.clone();
-        async move {
-            let connected = connected_clone;
-            while !*connected.lock().await {
-                sleep(Duration::from_millis(100)).await;
-            }
+    let rejected: Vec<_> = shards
+        .iter()
+        .flat_map(|shard| shard.processor.subscriptions.lock().unwrap().rejected())
+        .collect();
+    report_rejected(&rejected);
 
-            while !subscriptions.is_empty() {
-                let chunk_size = std::cmp::min(5000, subscriptions.len());
-                let chunk: Vec<_> = subscriptions.split_off(subscriptions.len() - chunk_size);
-                for item in chunk {
-                    let mut stream = stream_clone.lock().await;
-                    let command = format!("BQT {}\n", item.to_lowercase());
-                    if let Err(e) = stream.write_all(command.as_bytes()).await {
-                        println!("Error: BQT command - {:?}", e);
-                        reconnect(params.clone()).await.ok();
-                        return;
-                    }
-                    let command = format!("GQT {} S 1\n", item.to_lowercase());
-                    if let Err(e) = stream.write_all(command.as_bytes()).await {
-                        println!("Error: GQT command - {:?}", e);
-                        reconnect(params.clone()).await.ok();
-                        return;
-                    }
-                    let command = format!("SQT {}\n", item.to_lowercase());
-                    if let Err(e) = stream.write_all(command.as_bytes()).await {
-                        println!("Error: SQT command - {:?}", e);
-                        reconnect(params.clone()).await.ok();
-                        return;
-                    }
+    // * The writers drain and stop once the last handle is gone
+    if let Some(rotation) = rotation {
+        let _ = rotation.await;
+    }
+    let _ = drain.await;
+    pool.close_spool().await;
+    drop(pool);
+    writers.close();
+    writers.wait().await;
+
+    // * Partitions are sorted in place and merged into the session stream, kept alongside
+    merge_capture(&started, compression).await;
+
+    if auth_failures.is_empty() {
+        Ok(())
+    } else {
+        Err(auth_failures.join("; "))
+    }
+}
+
+// * Runs inside the session tracker, so stop() uploads only after the merge
+async fn merge_capture(started: &DateTime<Local>, compression: Compression) {
+    let output = capture::session_file(Path::new(CONTENT_DIR), started, compression);
+    let merged = output.clone();
+    match tokio::task::spawn_blocking(move || {
+        capture::merge_session(Path::new(CONTENT_DIR), &merged, compression)
+    })
+    .await
+    {
+        Ok(Ok(lines)) => info!(lines, output = %output.display(), "Merged capture files"),
+        Ok(Err(e)) => report_error(&format!("Error: merge capture files - {:?}", e)),
+        Err(e) => report_error(&format!("Error: merge task - {:?}", e)),
+    }
+}
+
+// * Returns the handshake error when the credentials were rejected
+async fn supervise(
+    shard: Arc<ShardContext>,
+    mut commands: mpsc::UnboundedReceiver<SubscriptionCommand>,
+    mut batcher: BatchWriter,
+    cancel: CancellationToken,
+) -> Option<HandshakeError> {
+    let mut endpoints = Endpoints::new(&shard.addresses, failover_threshold());
+    let failback = Duration::from_secs(failback_interval());
+    let label = format!("connection {}", shard.index);
+    let mut attempt: u32 = 0;
+    let mut auth_failure: Option<HandshakeError> = None;
+    while !cancel.is_cancelled() {
+        let connection = cancel.child_token();
+        let started = Instant::now();
+
+        let address = endpoints.current().to_string();
+        let primary = endpoints.primary().to_string();
+        let mut primary_back = false;
+        let result = {
+            let run = run_connection(
+                &shard,
+                &address,
+                &mut commands,
+                &mut batcher,
+                connection.clone(),
+            );
+            tokio::pin!(run);
+            tokio::select! {
+                result = &mut run => result,
+                _ = probe_endpoint(&shard, &primary, endpoints.failback_due(failback)), if !endpoints.on_primary() => {
+                    // * Stopped through its token so the lines already read reach the writers
+                    primary_back = true;
+                    connection.cancel();
+                    run.await
                 }
-                sleep(Duration::from_secs(5)).await;
             }
+        };
+        connection.cancel();
+
+        // * The primary is back, reconnect to it right away
+        if primary_back {
+            endpoints.failback(&label);
+            attempt = 0;
+            shard
+                .processor
+                .stats
+                .reconnects
+                .fetch_add(1, Ordering::SeqCst);
+            METRICS.reconnects.inc();
+            continue;
         }
-    });
 
-    tokio::spawn(async move {
-        loop {
-            if STOP_FLAG.load(Ordering::SeqCst) {
-                println!("Stopping cb");
+        match result {
+            Ok(()) => break,
+            // * Retrying with rejected credentials would only lock the account
+            Err(ConnectionError::AuthFailed(e)) => {
+                report_error(&format!(
+                    "Error: Crystal connection {} - {}",
+                    shard.index, e
+                ));
+                auth_failure = Some(e);
                 break;
             }
-            sleep(Duration::from_secs(1)).await;
-            println!(
-                "cb: {}, cr: {}",
-                message_count_report.load(Ordering::SeqCst),
-                total_lines_sent_report.load(Ordering::SeqCst)
-            );
+            Err(ConnectionError::Unreachable(reason)) => {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                warn!("{}", reason);
+                endpoints.failure(&label);
+            }
+            Err(ConnectionError::Lost(reason)) => {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                warn!("{}", reason);
+                // * A connection that drops right after the login counts against the endpoint
+                if started.elapsed() < Duration::from_secs(STABLE_CONNECTION) {
+                    endpoints.failure(&label);
+                } else {
+                    endpoints.success();
+                }
+            }
         }
-    });
 
-    let mut batch = Vec::with_capacity(BATCH_SIZE);
-    let mut writer_index = 0;
+        if started.elapsed() >= Duration::from_secs(STABLE_CONNECTION) {
+            attempt = 0;
+        }
+        let delay = backoff(attempt);
+        attempt = attempt.saturating_add(1);
+        shard
+            .processor
+            .stats
+            .reconnects
+            .fetch_add(1, Ordering::SeqCst);
+        METRICS.reconnects.inc();
+
+        info!(?delay, "Reconnecting");
+        sentry::capture_message("CMDC - RCT", Level::Info);
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = sleep(delay) => {}
+        }
+    }
+
+    if batcher.flush_pending().await.is_err() {
+        error!("Pending lines dropped, no writer accepted them");
+    }
+    auth_failure
+}
+
+// * Exponential backoff capped at RECONNECT_MAX_DELAY with equal jitter
+fn backoff(attempt: u32) -> Duration {
+    let delay = RECONNECT_BASE_DELAY
+        .saturating_mul(1u64 << attempt.min(16))
+        .min(RECONNECT_MAX_DELAY)
+        * 1000;
+    let jitter = rand::thread_rng().gen_range(0..=delay / 2);
+    Duration::from_millis(delay / 2 + jitter)
+}
 
+// * Resolves once the endpoint completes a login again, a port that accepts connections while
+// * the server is still starting is not enough. Failed probes wait the failback interval again
+async fn probe_endpoint(shard: &ShardContext, address: &str, due: Instant) {
+    let mut due = due;
     loop {
-        if STOP_FLAG.load(Ordering::SeqCst) {
-            println!("Stopping main loop");
-            break;
+        sleep_until(due).await;
+        match probe_login(shard, address).await {
+            Ok(()) => return,
+            Err(e) => debug!(address, error = %e, "Primary still down"),
         }
+        due = Instant::now() + Duration::from_secs(failback_interval());
+    }
+}
+
+async fn probe_login(shard: &ShardContext, address: &str) -> Result<(), Box<dyn Error>> {
+    let mut stream = timeout(
+        Duration::from_secs(CONNECT_TIMEOUT),
+        shard.transport.connect(address),
+    )
+    .await
+    .map_err(|_| "Error: connect timeout")??;
+    handshake::login(
+        &mut stream,
+        &shard.credentials.username,
+        &shard.credentials.password,
+    )
+    .await?;
+    Ok(())
+}
 
-        let mut chunk = vec![0; 16384];
-        let nbytes = {
+async fn run_connection(
+    shard: &ShardContext,
+    address: &str,
+    commands: &mut mpsc::UnboundedReceiver<SubscriptionCommand>,
+    batcher: &mut BatchWriter,
+    connection: CancellationToken,
+) -> Result<(), ConnectionError> {
+    health::connection_state(shard.index, SessionState::Connecting);
+    let connect = timeout(
+        Duration::from_secs(CONNECT_TIMEOUT),
+        shard.transport.connect(address),
+    );
+    let mut stream = tokio::select! {
+        _ = connection.cancelled() => return Ok(()),
+        result = connect => match result {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => {
+                let error_message = format!("Error: connect to Crystal {} [{}] - {:?}", address, shard.index, e);
+                report_error(&error_message);
+                return Err(ConnectionError::Unreachable(error_message));
+            }
+            Err(_) => {
+                let error_message = format!("Error: connect to Crystal {} [{}] - timeout", address, shard.index);
+                report_error(&error_message);
+                return Err(ConnectionError::Unreachable(error_message));
+            }
+        }
+    };
+
+    let login = handshake::login(
+        &mut stream,
+        &shard.credentials.username,
+        &shard.credentials.password,
+    );
+    let handshake_leftover = tokio::select! {
+        _ = connection.cancelled() => return Ok(()),
+        result = login => match result {
+            Ok(leftover) => leftover,
+            Err(e @ HandshakeError::AuthFailed(_)) => return Err(ConnectionError::AuthFailed(e)),
+            Err(e) => {
+                let error_message = format!("{} {} [{}]", e, address, shard.index);
+                report_error(&error_message);
+                return Err(ConnectionError::Unreachable(error_message));
+            }
+        }
+    };
+    info!(address, "Crystal connection authenticated");
+    health::connection_state(shard.index, SessionState::Authenticated);
+
+    // * A new connection gets a fresh book snapshot from Crystal
+    *shard.processor.books.lock().await = OrderBooks::default();
+
+    // * Queued commands are already reflected in the subscription set
+    while commands.try_recv().is_ok() {}
+    let to_subscribe = shard
+        .processor
+        .subscriptions
+        .lock()
+        .unwrap()
+        .reset_for_connection();
+
+    let (mut reader, writer) = tokio::io::split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+    let subscriber = tokio::spawn(
+        subscribe_all(
+            to_subscribe,
+            Arc::clone(&shard.processor.subscriptions),
+            Arc::clone(&writer),
+            connection.clone(),
+        )
+        .in_current_span(),
+    );
+
+    let result = read_stream(
+        &mut reader,
+        &writer,
+        commands,
+        handshake_leftover,
+        batcher,
+        shard,
+        &connection,
+    )
+    .await;
+
+    connection.cancel();
+    if let Err(e) = subscriber.await {
+        error!(error = ?e, "Subscription task failed");
+    }
+    result
+}
+
+async fn subscribe_all(
+    mut to_subscribe: Vec<(String, Vec<Feed>)>,
+    subscriptions: Arc<StdMutex<Subscriptions>>,
+    stream: Arc<Mutex<WriteHalf<BoxedStream>>>,
+    connection: CancellationToken,
+) {
+    while !to_subscribe.is_empty() {
+        let chunk_size = std::cmp::min(5000, to_subscribe.len());
+        let chunk: Vec<_> = to_subscribe.split_off(to_subscribe.len() - chunk_size);
+        for (symbol, feeds) in chunk {
             let mut stream = stream.lock().await;
-            match stream.read(&mut chunk).await {
+            for feed in feeds {
+                let command = feed.subscribe_command(&symbol);
+                if let Err(e) = stream.write_all(command.as_bytes()).await {
+                    error!(symbol = %symbol, command = &command[..3], error = ?e, "Send subscription command");
+                    connection.cancel();
+                    return;
+                }
+                subscriptions.lock().unwrap().sent(&symbol, feed);
+            }
+        }
+        tokio::select! {
+            _ = connection.cancelled() => return,
+            _ = sleep(Duration::from_secs(5)) => {}
+        }
+    }
+}
+
+async fn read_stream(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &Mutex<WriteHalf<BoxedStream>>,
+    commands: &mut mpsc::UnboundedReceiver<SubscriptionCommand>,
+    handshake_leftover: Vec<u8>,
+    batcher: &mut BatchWriter,
+    shard: &ShardContext,
+    connection: &CancellationToken,
+) -> Result<(), ConnectionError> {
+    let mut read_buffer = Vec::with_capacity(16384);
+    read_buffer.extend_from_slice(&handshake_leftover);
+    let mut chunk = vec![0; 16384];
+    let mut watchdog = IdleWatchdog::new();
+    let mut streaming = false;
+
+    loop {
+        let ping_deadline = watchdog.ping_deadline();
+        let nbytes = tokio::select! {
+            _ = connection.cancelled() => {
+                debug!("Stopping main loop");
+                return Err(ConnectionError::Lost(
+                    "Error: connection cancelled".to_string(),
+                ));
+            }
+            result = reader.read(&mut chunk) => match result {
                 Ok(n) => n,
                 Err(e) => {
-                    println!("Error: read stream - {:?}", e);
-                    reconnect(params.clone()).await?;
-                    return Ok(());
+                    return Err(ConnectionError::Lost(format!("Error: read stream - {:?}", e)));
+                }
+            },
+            Some(command) = commands.recv() => {
+                let text = command.command();
+                if let Err(e) = writer.lock().await.write_all(text.as_bytes()).await {
+                    return Err(ConnectionError::Lost(format!(
+                        "Error: {} command - {:?}",
+                        &text[..3],
+                        e
+                    )));
+                }
+                if command.subscribe {
+                    shard.processor.subscriptions.lock().unwrap().sent(&command.symbol, command.feed);
+                }
+                continue;
+            }
+            _ = sleep_until(ping_deadline.unwrap_or_else(Instant::now)), if ping_deadline.is_some() => {
+                if let Some(ping) = watchdog.take_ping() {
+                    info!(idle = ?watchdog.idle_for(), "Sending ping");
+                    if let Err(e) = writer.lock().await.write_all(ping.as_bytes()).await {
+                        return Err(ConnectionError::Lost(format!("Error: send ping - {:?}", e)));
+                    }
                 }
+                continue;
+            }
+            _ = sleep_until(watchdog.idle_deadline()) => {
+                let error_message = format!(
+                    "Error: no data from Crystal [{}] for {:?}",
+                    shard.index,
+                    watchdog.idle_for()
+                );
+                sentry::capture_message(
+                    &format!("CMDC - IDLE {}s", watchdog.idle_for().as_secs()),
+                    Level::Warning,
+                );
+                return Err(ConnectionError::Lost(error_message));
             }
         };
         if nbytes == 0 {
-            println!("FIN");
-            reconnect(params.clone()).await?;
-            return Ok(());
+            return Err(ConnectionError::Lost("FIN".to_string()));
+        }
+        watchdog.feed();
+        health::feed();
+        if !streaming {
+            streaming = true;
+            health::connection_state(shard.index, SessionState::Streaming);
         }
 
         read_buffer.extend_from_slice(&chunk[..nbytes]);
@@ -251,47 +720,22 @@ async fn start_async(params: CrystalParams) -> Result<(), Box<dyn std::error::Er
             let line_end = pos + newline_pos + 1;
             let line = &read_buffer[pos..line_end];
             pos = line_end;
-
-            let now: DateTime<Local> = Local::now();
-            batch.push((now, line.to_vec()));
-
-            if batch.len() >= BATCH_SIZE {
-                if let Err(_) = txs[writer_index].send(batch.clone()).await {
-                    println!("[DROP]");
-                    break;
-                }
-                message_count.fetch_add(batch.len(), Ordering::SeqCst);
-                total_lines_sent.fetch_add(batch.len(), Ordering::SeqCst);
-                batch.clear();
-                writer_index = (writer_index + 1) % NUM_WRITERS;
+            METRICS.received_lines.inc();
+            METRICS.received_bytes.inc_by(line.len() as u64);
+
+            let (received, monotonic) = batcher.clock();
+            let event = shard.processor.process(line).await;
+            let stamp = Stamp {
+                received,
+                monotonic,
+                exchange: event.as_ref().and_then(MarketEvent::exchange_time),
+            };
+            if batcher.push(stamp, line.to_vec()).await.is_err() {
+                error!("Line dropped, no writer accepted it");
             }
 
-            if let Ok(line_string) = std::str::from_utf8(line) {
-                if line_string.contains("Connecting...") {
-                    let mut stream = stream.lock().await;
-                    if let Err(e) = stream.write_all(b"\n").await {
-                        println!("Error: send ln - {:?}", e);
-                        reconnect(params.clone()).await?;
-                        return Ok(());
-                    }
-                } else if line_string.contains("Username:") {
-                    let mut stream = stream.lock().await;
-                    if let Err(e) = stream.write_all(params.mkt_data_username.as_bytes()).await {
-                        println!("Error: send username - {:?}", e);
-                        reconnect(params.clone()).await?;
-                        return Ok(());
-                    }
-                } else if line_string.contains("Password:") {
-                    let mut stream = stream.lock().await;
-                    if let Err(e) = stream.write_all(params.mkt_data_password.as_bytes()).await {
-                        println!("Error: send password - {:?}", e);
-                        reconnect(params.clone()).await?;
-                        return Ok(());
-                    }
-                } else if line_string.contains("You are connected") {
-                    let mut connected = connected.lock().await;
-                    *connected = true;
-                }
+            if let Some(event) = event {
+                batcher.record(event).await;
             }
         }
 
@@ -301,54 +745,193 @@ async fn start_async(params: CrystalParams) -> Result<(), Box<dyn std::error::Er
             read_buffer.drain(..pos);
         }
     }
+}
 
-    if !batch.is_empty() {
-        println!(
-            "Sending f-batch to writer ({} messages) - W:{}",
-            batch.len(),
-            writer_index
-        );
-        if let Err(_) = txs[writer_index].send(batch.clone()).await {
-            println!("[f-DROP]");
+async fn report_stats(stats: Arc<CaptureStats>, cancel: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                debug!("Stopping stats report");
+                break;
+            }
+            _ = sleep(Duration::from_secs(1)) => info!("{}", stats.report()),
         }
-        message_count.fetch_add(batch.len(), Ordering::SeqCst);
-        total_lines_sent.fetch_add(batch.len(), Ordering::SeqCst);
     }
+}
 
-    Ok(())
+async fn snapshot_books(books: Vec<Arc<Mutex<OrderBooks>>>, cancel: CancellationToken) {
+    let mut snapshot_interval = interval(Duration::from_secs(BOOK_SNAPSHOT_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                debug!("Stopping book snapshots");
+                break;
+            }
+            _ = snapshot_interval.tick() => {}
+        }
+        let time = Local::now().format(TIMESTAMP_FORMAT).to_string();
+        let mut snapshot = String::new();
+        for books in &books {
+            snapshot.push_str(&books.lock().await.format_snapshot(&time, BOOK_DEPTH));
+        }
+        if snapshot.is_empty() {
+            continue;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open("content/crystal-books.txt")
+            .await;
+        match file {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(snapshot.as_bytes()).await {
+                    error!(error = ?e, "Write book snapshot");
+                }
+            }
+            Err(e) => error!(error = ?e, "Open book snapshot file"),
+        }
+    }
 }
 
-async fn reconnect(params: Arc<CrystalParams>) -> Result<(), Box<dyn std::error::Error>> {
-    STOP_FLAG.store(true, Ordering::SeqCst);
-    sleep(Duration::from_secs(RECONNECT_DELAY)).await;
-    STOP_FLAG.store(false, Ordering::SeqCst);
-    println!("Reconnecting...");
-    sentry::capture_message("CMDC - RCT", Level::Info);
-    start(
-        params.assets.clone(),
-        params.mkt_data_address.clone(),
-        params.mkt_data_username.clone(),
-        params.mkt_data_password.clone(),
-    )
-    .await?;
-    Ok(())
+// * Applies the subscriptions file at the session start and whenever it changes. Requests already
+// * in effect are skipped, so the whole file is applied each time
+async fn watch_requests(path: PathBuf, cancel: CancellationToken) {
+    let mut poll = interval(Duration::from_secs(SUBSCRIPTIONS_POLL));
+    let mut applied = None;
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                debug!("Stopping subscription requests");
+                break;
+            }
+            _ = poll.tick() => {}
+        }
+        let modified = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(e) => {
+                if applied.is_some() {
+                    warn!(file = %path.display(), error = %e, "Read subscriptions file");
+                    applied = None;
+                }
+                continue;
+            }
+        };
+        if modified.is_some() && modified == applied {
+            continue;
+        }
+        match tokio::fs::read_to_string(&path).await {
+            Ok(requests) => {
+                apply_requests(&path, &requests);
+                applied = modified;
+            }
+            Err(e) => warn!(file = %path.display(), error = %e, "Read subscriptions file"),
+        }
+    }
+}
+
+fn apply_requests(path: &Path, requests: &str) {
+    let mut changed = 0;
+    for (number, line) in requests.lines().enumerate() {
+        let request = match SubscriptionRequest::parse(line) {
+            Some(Ok(request)) => request,
+            Some(Err(e)) => {
+                warn!(file = %path.display(), line = number + 1, error = %e, "Subscription request");
+                continue;
+            }
+            None => continue,
+        };
+        for feed in request.feeds {
+            match update_subscription(&request.symbol, feed, request.subscribe) {
+                Ok(true) => changed += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    warn!(file = %path.display(), line = number + 1, error = %e, "Subscription request")
+                }
+            }
+        }
+    }
+    info!(file = %path.display(), changed, "Subscription requests applied");
+}
+
+// * Uploaded with the capture so the asset list can be reviewed
+fn report_rejected(rejected: &[(String, Feed, String)]) {
+    if rejected.is_empty() {
+        return;
+    }
+    let mut report = String::new();
+    for (symbol, feed, reason) in rejected {
+        warn!(symbol = %symbol, feed = %feed, reason = %reason, "Subscription rejected");
+        report.push_str(&format!("{} {} {}\n", symbol, feed, reason));
+    }
+    if let Err(e) = std::fs::write("content/crystal-rejected.txt", report) {
+        error!(error = ?e, "Write rejected subscriptions");
+    }
+    sentry::capture_message(
+        &format!("CMDC - {} rejected subscriptions", rejected.len()),
+        Level::Warning,
+    );
+}
+
+// * Resets the health state when the upload ends, fails or panics
+struct Uploading;
+
+impl Uploading {
+    fn start() -> Uploading {
+        health::uploading(true);
+        Uploading
+    }
+}
+
+impl Drop for Uploading {
+    fn drop(&mut self) {
+        health::uploading(false);
+    }
 }
 
-pub async fn stop() {
-    STOP_FLAG.store(true, Ordering::SeqCst);
-    println!("[Stop signal]");
+// * Sends whatever is in content/, segments whose upload failed first
+pub async fn upload() {
+    let _upload = UPLOAD.lock().await;
+    let _uploading = Uploading::start();
+    if let Err(e) = upload_content().await {
+        report_error(&format!("Error: upload {} - {}", CONTENT_DIR, e));
+    }
+}
 
-    let account = vault::get_secret(BLOB_ACCOUNT, &vault_url()).await.unwrap();
-    let container = vault::get_secret(BLOB_CONTAINER, &vault_url())
-        .await
-        .unwrap();
-    let key = vault::get_secret(BLOB_KEY, &vault_url()).await.unwrap();
+async fn upload_content() -> Result<(), Box<dyn Error>> {
+    let account = vault::get_secret(BLOB_ACCOUNT, &vault_url()).await?;
+    let container = vault::get_secret(BLOB_CONTAINER, &vault_url()).await?;
+    let key = vault::get_secret(BLOB_KEY, &vault_url()).await?;
 
-    let local_path = format!("content");
+    let local_path = CONTENT_DIR;
 
-    if let Err(e) = storage::upload_to_blob(&account, &container, &local_path, &key).await {
-        println!("Error: upload to blob - {}", e);
+    segments::upload_pending().await;
+    match segments::final_name() {
+        Some(name) => {
+            let date = Utc::now().format("%Y-%m-%d").to_string();
+            storage::upload_folder_to_blob(&account, &container, local_path, &name, &date, &key)
+                .await
+        }
+        None => storage::upload_to_blob(&account, &container, local_path, &key).await,
     }
+}
 
-    sentry::capture_message("CMDC has finished", Level::Info);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        for (attempt, max) in [(0, 1), (1, 2), (3, 8), (6, 60), (40, 60)] {
+            let max = Duration::from_secs(max);
+            for _ in 0..100 {
+                let delay = backoff(attempt);
+                assert!(
+                    delay >= max / 2 && delay <= max,
+                    "{} {:?}",
+                    attempt,
+                    delay
+                );
+            }
+        }
+    }
 }
@@ -1,7 +1,15 @@
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
 #[derive(Clone)]
 pub struct CrystalParams {
     pub assets: Vec<String>,
-    pub mkt_data_address: String,
-    pub mkt_data_username: String,
-    pub mkt_data_password: String,
+    // * Primary endpoint first, then the backups in order
+    pub mkt_data_addresses: Vec<String>,
+    // * Connection i logs in with credentials[i % credentials.len()]
+    pub credentials: Vec<Credentials>,
+    pub connections: usize,
 }
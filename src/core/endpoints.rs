@@ -0,0 +1,186 @@
+use sentry::Level;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// * Health of one Crystal endpoint
+#[derive(Debug, Clone)]
+struct Endpoint {
+    address: String,
+    consecutive_failures: u32,
+    last_failure: Option<Instant>,
+}
+
+// * Ordered list of endpoints, the first one is the primary
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    endpoints: Vec<Endpoint>,
+    current: usize,
+    failover_threshold: u32,
+}
+
+impl Endpoints {
+    pub fn new(addresses: &[String], failover_threshold: u32) -> Endpoints {
+        Endpoints {
+            endpoints: addresses
+                .iter()
+                .map(|address| Endpoint {
+                    address: address.clone(),
+                    consecutive_failures: 0,
+                    last_failure: None,
+                })
+                .collect(),
+            current: 0,
+            failover_threshold: failover_threshold.max(1),
+        }
+    }
+
+    pub fn current(&self) -> &str {
+        &self.endpoints[self.current].address
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.endpoints[0].address
+    }
+
+    pub fn on_primary(&self) -> bool {
+        self.current == 0
+    }
+
+    // * The primary is probed once it has been quiet for the interval since its last failure
+    pub fn failback_due(&self, quiet: Duration) -> Instant {
+        self.endpoints[0]
+            .last_failure
+            .map_or_else(Instant::now, |at| at + quiet)
+    }
+
+    pub fn success(&mut self) {
+        self.endpoints[self.current].consecutive_failures = 0;
+    }
+
+    // * Moves to the next endpoint after failover_threshold consecutive failures
+    pub fn failure(&mut self, label: &str) {
+        let count = self.endpoints.len();
+        let endpoint = &mut self.endpoints[self.current];
+        endpoint.consecutive_failures += 1;
+        endpoint.last_failure = Some(Instant::now());
+        if endpoint.consecutive_failures < self.failover_threshold || count < 2 {
+            return;
+        }
+
+        let from = endpoint.address.clone();
+        endpoint.consecutive_failures = 0;
+        self.current = (self.current + 1) % count;
+        warn!(from = %from, to = self.current(), "Failover {}", label);
+        sentry::capture_message(
+            &format!("CMDC - FAILOVER {} {} -> {}", label, from, self.current()),
+            Level::Warning,
+        );
+    }
+
+    pub fn failback(&mut self, label: &str) {
+        if self.on_primary() {
+            return;
+        }
+        let from = self.current().to_string();
+        self.current = 0;
+        self.endpoints[0].consecutive_failures = 0;
+        let down_for = self.endpoints[0].last_failure.map(|at| at.elapsed());
+        info!(
+            from = %from,
+            to = self.primary(),
+            down_for = ?down_for.unwrap_or_default(),
+            "Failback {}",
+            label
+        );
+        sentry::capture_message(
+            &format!("CMDC - FAILBACK {} {} -> {}", label, from, self.primary()),
+            Level::Warning,
+        );
+    }
+}
+
+// * Format: host:port,host:port with the primary first
+pub fn parse_addresses(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints(threshold: u32) -> Endpoints {
+        Endpoints::new(
+            &parse_addresses(" primary:9000, backup:9000,,dr:9000 "),
+            threshold,
+        )
+    }
+
+    #[test]
+    fn parses_the_address_list() {
+        assert_eq!(
+            parse_addresses(" primary:9000, backup:9000,,dr:9000 "),
+            ["primary:9000", "backup:9000", "dr:9000"]
+        );
+        assert!(parse_addresses("").is_empty());
+    }
+
+    #[test]
+    fn fails_over_in_order_after_the_threshold() {
+        let mut endpoints = endpoints(2);
+        endpoints.failure("shard 0");
+        assert_eq!(endpoints.current(), "primary:9000");
+        endpoints.failure("shard 0");
+        assert_eq!(endpoints.current(), "backup:9000");
+        assert!(!endpoints.on_primary());
+
+        // * A success in between starts the count again
+        endpoints.failure("shard 0");
+        endpoints.success();
+        endpoints.failure("shard 0");
+        assert_eq!(endpoints.current(), "backup:9000");
+        endpoints.failure("shard 0");
+        assert_eq!(endpoints.current(), "dr:9000");
+
+        // * The last endpoint wraps around to the primary
+        endpoints.failure("shard 0");
+        endpoints.failure("shard 0");
+        assert_eq!(endpoints.current(), "primary:9000");
+    }
+
+    #[test]
+    fn a_single_endpoint_never_fails_over() {
+        let mut endpoints = Endpoints::new(&["primary:9000".to_string()], 1);
+        for _ in 0..3 {
+            endpoints.failure("shard 0");
+        }
+        assert_eq!(endpoints.current(), "primary:9000");
+    }
+
+    #[test]
+    fn fails_back_to_the_primary() {
+        let mut endpoints = endpoints(1);
+        endpoints.failure("shard 0");
+        endpoints.failure("shard 0");
+        assert_eq!(endpoints.current(), "dr:9000");
+
+        endpoints.failback("shard 0");
+        assert!(endpoints.on_primary());
+        assert_eq!(endpoints.current(), endpoints.primary());
+    }
+
+    #[test]
+    fn probes_the_primary_once_quiet_since_its_last_failure() {
+        let quiet = Duration::from_secs(60);
+        let mut endpoints = endpoints(1);
+        assert!(endpoints.failback_due(quiet) <= Instant::now());
+
+        let failed = Instant::now();
+        endpoints.failure("shard 0");
+        let due = endpoints.failback_due(quiet);
+        assert!(due >= failed + quiet && due <= Instant::now() + quiet);
+    }
+}
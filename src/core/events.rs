@@ -0,0 +1,489 @@
+use chrono::{DateTime, Local, NaiveTime, Timelike, Utc};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DataType, DoubleType, Int32Type, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use std::fs::{rename, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+use super::bqt::{BookEvent, BookMessage, DeleteKind, Order, Side};
+use super::budget::Reservation;
+use super::gqt::{Aggressor, TradeMessage};
+use super::segments;
+use super::sqt::{QuoteField, QuoteUpdate};
+use tracing::{error, info};
+
+const EVENTS_PREFIX: &str = "crystal-events-";
+const ROW_GROUP_SIZE: usize = 100000;
+pub const MAX_EVENT_BUFFER: usize = 1000000;
+// * Bytes of the memory budget held by one queued event
+pub const EVENT_SIZE: usize = 512;
+
+// * One row per normalized event, columns that do not apply to the event are null
+const SCHEMA: &str = "
+message crystal_event {
+    REQUIRED INT64 seq;
+    REQUIRED INT64 received_at (TIMESTAMP(NANOS, true));
+    REQUIRED BYTE_ARRAY event (STRING);
+    REQUIRED BYTE_ARRAY symbol (STRING);
+    OPTIONAL INT32 exchange_time (TIME(MILLIS, false));
+    OPTIONAL DOUBLE price;
+    OPTIONAL INT64 quantity;
+    OPTIONAL BYTE_ARRAY side (STRING);
+    OPTIONAL DOUBLE bid;
+    OPTIONAL DOUBLE ask;
+    OPTIONAL INT64 bid_volume;
+    OPTIONAL INT64 ask_volume;
+    OPTIONAL INT64 volume;
+    OPTIONAL DOUBLE financial_volume;
+    OPTIONAL INT64 traded_quantity;
+    OPTIONAL INT64 trade_count;
+    OPTIONAL DOUBLE high;
+    OPTIONAL DOUBLE low;
+    OPTIONAL DOUBLE open;
+    OPTIONAL DOUBLE previous_close;
+    OPTIONAL BYTE_ARRAY buyer (STRING);
+    OPTIONAL BYTE_ARRAY seller (STRING);
+    OPTIONAL BYTE_ARRAY trade_id (STRING);
+    OPTIONAL INT32 position;
+    OPTIONAL INT32 old_position;
+    OPTIONAL BYTE_ARRAY delete_kind (STRING);
+    OPTIONAL BYTE_ARRAY broker (STRING);
+    OPTIONAL BYTE_ARRAY order_id (STRING);
+    OPTIONAL BYTE_ARRAY order_type (STRING);
+    OPTIONAL BYTE_ARRAY order_time (STRING);
+}
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarketEvent {
+    Quote(QuoteUpdate),
+    Trade(TradeMessage),
+    Book(BookMessage),
+}
+
+impl MarketEvent {
+    // * Quotes and trades carry the time of the event, book orders only their creation time
+    pub fn exchange_time(&self) -> Option<NaiveTime> {
+        match self {
+            MarketEvent::Quote(update) => Some(update.time),
+            MarketEvent::Trade(TradeMessage::Trade(trade)) => Some(trade.time),
+            _ => None,
+        }
+    }
+}
+
+// * The event with the sequence number and arrival time of the line it came from
+pub struct EventRecord {
+    pub seq: u64,
+    pub received: DateTime<Utc>,
+    pub event: MarketEvent,
+}
+
+pub enum EventMessage {
+    // * The reservation is given back once the event is a row
+    Record(EventRecord, Reservation),
+    // * Closes the open file and moves the files closed since the last rotation into the folder
+    Rotate(PathBuf, oneshot::Sender<()>),
+}
+
+#[derive(Default)]
+struct Row {
+    seq: i64,
+    received_at: i64,
+    event: &'static str,
+    symbol: String,
+    exchange_time: Option<i32>,
+    price: Option<f64>,
+    quantity: Option<i64>,
+    side: Option<&'static str>,
+    bid: Option<f64>,
+    ask: Option<f64>,
+    bid_volume: Option<i64>,
+    ask_volume: Option<i64>,
+    volume: Option<i64>,
+    financial_volume: Option<f64>,
+    traded_quantity: Option<i64>,
+    trade_count: Option<i64>,
+    high: Option<f64>,
+    low: Option<f64>,
+    open: Option<f64>,
+    previous_close: Option<f64>,
+    buyer: Option<String>,
+    seller: Option<String>,
+    trade_id: Option<String>,
+    position: Option<i32>,
+    old_position: Option<i32>,
+    delete_kind: Option<&'static str>,
+    broker: Option<String>,
+    order_id: Option<String>,
+    order_type: Option<String>,
+    order_time: Option<String>,
+}
+
+impl Row {
+    fn from_record(record: EventRecord) -> Row {
+        let mut row = Row {
+            seq: record.seq as i64,
+            received_at: record.received.timestamp_nanos_opt().unwrap_or_default(),
+            ..Default::default()
+        };
+        match record.event {
+            MarketEvent::Quote(update) => row.quote(update),
+            MarketEvent::Trade(message) => row.trade(message),
+            MarketEvent::Book(message) => row.book(message),
+        }
+        row
+    }
+
+    // * Indexes without a column (dates, bid/ask times and raw values) are left out
+    fn quote(&mut self, update: QuoteUpdate) {
+        self.event = "quote";
+        self.symbol = update.symbol;
+        self.exchange_time = Some(millis(&update.time));
+        for field in update.fields {
+            match field {
+                QuoteField::LastPrice(value) => self.price = Some(value),
+                QuoteField::LastTradeQuantity(value) => self.quantity = Some(value as i64),
+                QuoteField::Bid(value) => self.bid = Some(value),
+                QuoteField::Ask(value) => self.ask = Some(value),
+                QuoteField::BidVolume(value) => self.bid_volume = Some(value as i64),
+                QuoteField::AskVolume(value) => self.ask_volume = Some(value as i64),
+                QuoteField::Volume(value) => self.volume = Some(value as i64),
+                QuoteField::FinancialVolume(value) => self.financial_volume = Some(value),
+                QuoteField::TradedQuantity(value) => self.traded_quantity = Some(value as i64),
+                QuoteField::TradeCount(value) => self.trade_count = Some(value as i64),
+                QuoteField::High(value) => self.high = Some(value),
+                QuoteField::Low(value) => self.low = Some(value),
+                QuoteField::Open(value) => self.open = Some(value),
+                QuoteField::PreviousClose(value) => self.previous_close = Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    fn trade(&mut self, message: TradeMessage) {
+        match message {
+            TradeMessage::Trade(trade) => {
+                self.event = "trade";
+                self.symbol = trade.symbol;
+                self.exchange_time = Some(millis(&trade.time));
+                self.price = Some(trade.price);
+                self.quantity = Some(trade.quantity as i64);
+                self.side = trade.aggressor.map(|aggressor| match aggressor {
+                    Aggressor::Buyer => "buyer",
+                    Aggressor::Seller => "seller",
+                });
+                self.buyer = Some(trade.buyer);
+                self.seller = Some(trade.seller);
+                self.trade_id = Some(trade.trade_id);
+            }
+            TradeMessage::Cancel { symbol, trade_id } => {
+                self.event = "trade_cancel";
+                self.symbol = symbol;
+                self.trade_id = Some(trade_id);
+            }
+            TradeMessage::Reset { symbol } => {
+                self.event = "trade_reset";
+                self.symbol = symbol;
+            }
+        }
+    }
+
+    fn book(&mut self, message: BookMessage) {
+        self.symbol = message.symbol;
+        match message.event {
+            BookEvent::Add { position, order } => {
+                self.event = "book_add";
+                self.position = Some(position as i32);
+                self.order(order);
+            }
+            BookEvent::Update {
+                position,
+                old_position,
+                order,
+            } => {
+                self.event = "book_update";
+                self.position = Some(position as i32);
+                self.old_position = Some(old_position as i32);
+                self.order(order);
+            }
+            BookEvent::Delete {
+                kind,
+                side,
+                position,
+            } => {
+                self.event = "book_delete";
+                self.delete_kind = Some(match kind {
+                    DeleteKind::Single => "single",
+                    DeleteKind::UpTo => "up_to",
+                    DeleteKind::All => "all",
+                });
+                self.side = Some(side_name(side));
+                self.position = Some(position as i32);
+            }
+            BookEvent::Snapshot => self.event = "book_snapshot",
+        }
+    }
+
+    fn order(&mut self, order: Order) {
+        self.side = Some(side_name(order.side));
+        self.price = Some(order.price);
+        self.quantity = Some(order.quantity as i64);
+        self.broker = Some(order.broker);
+        self.order_time = Some(order.time);
+        self.order_id = Some(order.order_id);
+        self.order_type = Some(order.order_type);
+    }
+}
+
+fn side_name(side: Side) -> &'static str {
+    match side {
+        Side::Bid => "bid",
+        Side::Ask => "ask",
+    }
+}
+
+fn millis(time: &NaiveTime) -> i32 {
+    (time.num_seconds_from_midnight() * 1000 + time.nanosecond() / 1000000) as i32
+}
+
+struct EventFile {
+    writer: SerializedFileWriter<File>,
+    partial: PathBuf,
+    path: PathBuf,
+    rows: usize,
+}
+
+// * Rows are buffered into row groups and the file is rolled every rows_per_file rows.
+// * A file is written as .partial and only gets its final name once the footer is on disk
+pub fn run_event_writer(
+    mut rx: mpsc::Receiver<EventMessage>,
+    dir: PathBuf,
+    rows_per_file: usize,
+    started: DateTime<Local>,
+) {
+    let schema = match parse_message_type(SCHEMA) {
+        Ok(schema) => Arc::new(schema),
+        Err(e) => {
+            error!(error = ?e, "Parquet schema");
+            return;
+        }
+    };
+    let properties = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_created_by("chita-mdc".to_string())
+            .build(),
+    );
+
+    let mut part = 0;
+    let mut file: Option<EventFile> = None;
+    let mut rows: Vec<Row> = Vec::with_capacity(ROW_GROUP_SIZE);
+    let mut closed: Vec<PathBuf> = Vec::new();
+    loop {
+        let (rotate, finished) = match rx.blocking_recv() {
+            Some(EventMessage::Record(record, _reservation)) => {
+                rows.push(Row::from_record(record));
+                (None, false)
+            }
+            Some(EventMessage::Rotate(dir, ack)) => (Some((dir, ack)), false),
+            None => (None, true),
+        };
+        let close = finished || rotate.is_some();
+
+        let written = file.as_ref().map_or(0, |file| file.rows);
+        let group_size = ROW_GROUP_SIZE.min(rows_per_file - written);
+        if rows.len() >= group_size || (close && !rows.is_empty()) {
+            if file.is_none() {
+                part += 1;
+                match open_event_file(&dir, &schema, &properties, &started, part) {
+                    Ok(opened) => file = Some(opened),
+                    Err(e) => error!(error = ?e, "Open parquet file"),
+                }
+            }
+            if let Some(file) = file.as_mut() {
+                match write_row_group(&mut file.writer, &rows) {
+                    Ok(()) => file.rows += rows.len(),
+                    Err(e) => error!(
+                        rows = rows.len(),
+                        file = %file.partial.display(),
+                        error = ?e,
+                        "Write parquet rows"
+                    ),
+                }
+            }
+            rows.clear();
+        }
+
+        let full = file.as_ref().is_some_and(|file| file.rows >= rows_per_file);
+        if full || close {
+            if let Some(path) = file.take().and_then(close_event_file) {
+                closed.push(path);
+            }
+        }
+        if let Some((dir, ack)) = rotate {
+            for path in closed.drain(..) {
+                segments::move_into(&path, &dir);
+            }
+            let _ = ack.send(());
+        }
+        if finished {
+            break;
+        }
+    }
+}
+
+fn open_event_file(
+    dir: &Path,
+    schema: &Arc<parquet::schema::types::Type>,
+    properties: &Arc<WriterProperties>,
+    started: &DateTime<Local>,
+    part: usize,
+) -> Result<EventFile, ParquetError> {
+    let name = format!(
+        "{}{}-{:04}.parquet",
+        EVENTS_PREFIX,
+        started.format("%H%M%S"),
+        part
+    );
+    let path = dir.join(name);
+    let partial = path.with_extension("parquet.partial");
+    let writer = SerializedFileWriter::new(
+        File::create(&partial)?,
+        Arc::clone(schema),
+        Arc::clone(properties),
+    )?;
+    Ok(EventFile {
+        writer,
+        partial,
+        path,
+        rows: 0,
+    })
+}
+
+fn close_event_file(file: EventFile) -> Option<PathBuf> {
+    if let Err(e) = file.writer.close() {
+        error!(file = %file.partial.display(), error = ?e, "Close parquet file");
+        return None;
+    }
+    match rename(&file.partial, &file.path) {
+        Ok(()) => {
+            info!(file = %file.path.display(), rows = file.rows, "Closed parquet file");
+            Some(file.path)
+        }
+        Err(e) => {
+            error!(file = %file.partial.display(), error = ?e, "Rename parquet file");
+            None
+        }
+    }
+}
+
+// * Columns are written in the order of SCHEMA
+fn write_row_group(
+    writer: &mut SerializedFileWriter<File>,
+    rows: &[Row],
+) -> Result<(), ParquetError> {
+    let mut group = writer.next_row_group()?;
+    required::<Int64Type>(&mut group, rows.iter().map(|row| row.seq).collect())?;
+    required::<Int64Type>(&mut group, rows.iter().map(|row| row.received_at).collect())?;
+    required::<ByteArrayType>(
+        &mut group,
+        rows.iter().map(|row| ByteArray::from(row.event)).collect(),
+    )?;
+    required::<ByteArrayType>(
+        &mut group,
+        rows.iter()
+            .map(|row| ByteArray::from(row.symbol.as_str()))
+            .collect(),
+    )?;
+    optional::<Int32Type>(
+        &mut group,
+        rows.iter().map(|row| row.exchange_time).collect(),
+    )?;
+    optional::<DoubleType>(&mut group, rows.iter().map(|row| row.price).collect())?;
+    optional::<Int64Type>(&mut group, rows.iter().map(|row| row.quantity).collect())?;
+    optional::<ByteArrayType>(
+        &mut group,
+        rows.iter()
+            .map(|row| row.side.map(ByteArray::from))
+            .collect(),
+    )?;
+    optional::<DoubleType>(&mut group, rows.iter().map(|row| row.bid).collect())?;
+    optional::<DoubleType>(&mut group, rows.iter().map(|row| row.ask).collect())?;
+    optional::<Int64Type>(&mut group, rows.iter().map(|row| row.bid_volume).collect())?;
+    optional::<Int64Type>(&mut group, rows.iter().map(|row| row.ask_volume).collect())?;
+    optional::<Int64Type>(&mut group, rows.iter().map(|row| row.volume).collect())?;
+    optional::<DoubleType>(
+        &mut group,
+        rows.iter().map(|row| row.financial_volume).collect(),
+    )?;
+    optional::<Int64Type>(
+        &mut group,
+        rows.iter().map(|row| row.traded_quantity).collect(),
+    )?;
+    optional::<Int64Type>(&mut group, rows.iter().map(|row| row.trade_count).collect())?;
+    optional::<DoubleType>(&mut group, rows.iter().map(|row| row.high).collect())?;
+    optional::<DoubleType>(&mut group, rows.iter().map(|row| row.low).collect())?;
+    optional::<DoubleType>(&mut group, rows.iter().map(|row| row.open).collect())?;
+    optional::<DoubleType>(
+        &mut group,
+        rows.iter().map(|row| row.previous_close).collect(),
+    )?;
+    optional::<ByteArrayType>(&mut group, strings(rows, |row| &row.buyer))?;
+    optional::<ByteArrayType>(&mut group, strings(rows, |row| &row.seller))?;
+    optional::<ByteArrayType>(&mut group, strings(rows, |row| &row.trade_id))?;
+    optional::<Int32Type>(&mut group, rows.iter().map(|row| row.position).collect())?;
+    optional::<Int32Type>(
+        &mut group,
+        rows.iter().map(|row| row.old_position).collect(),
+    )?;
+    optional::<ByteArrayType>(
+        &mut group,
+        rows.iter()
+            .map(|row| row.delete_kind.map(ByteArray::from))
+            .collect(),
+    )?;
+    optional::<ByteArrayType>(&mut group, strings(rows, |row| &row.broker))?;
+    optional::<ByteArrayType>(&mut group, strings(rows, |row| &row.order_id))?;
+    optional::<ByteArrayType>(&mut group, strings(rows, |row| &row.order_type))?;
+    optional::<ByteArrayType>(&mut group, strings(rows, |row| &row.order_time))?;
+    group.close()?;
+    Ok(())
+}
+
+fn strings(rows: &[Row], field: impl Fn(&Row) -> &Option<String>) -> Vec<Option<ByteArray>> {
+    rows.iter()
+        .map(|row| field(row).as_deref().map(ByteArray::from))
+        .collect()
+}
+
+fn required<T: DataType>(
+    group: &mut SerializedRowGroupWriter<'_, File>,
+    values: Vec<T::T>,
+) -> Result<(), ParquetError> {
+    let mut column = group
+        .next_column()?
+        .ok_or_else(|| ParquetError::General("more columns than the schema".to_string()))?;
+    column.typed::<T>().write_batch(&values, None, None)?;
+    column.close()
+}
+
+fn optional<T: DataType>(
+    group: &mut SerializedRowGroupWriter<'_, File>,
+    values: Vec<Option<T::T>>,
+) -> Result<(), ParquetError> {
+    let levels: Vec<i16> = values.iter().map(|value| value.is_some() as i16).collect();
+    let present: Vec<T::T> = values.into_iter().flatten().collect();
+    let mut column = group
+        .next_column()?
+        .ok_or_else(|| ParquetError::General("more columns than the schema".to_string()))?;
+    column
+        .typed::<T>()
+        .write_batch(&present, Some(&levels), None)?;
+    column.close()
+}
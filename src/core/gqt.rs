@@ -0,0 +1,157 @@
+use chrono::NaiveTime;
+
+use super::parse::{
+    invalid, message_body, next_field, parse_price, parse_quantity, parse_time, ParseError,
+};
+
+// * Format: V:<symbol>:<operation>:<time>:<price>:<buyer>:<seller>:<quantity>:<trade id>:<request type>:<aggressor>!
+#[derive(Debug, Clone, PartialEq)]
+pub enum TradeMessage {
+    Trade(Trade),
+    // * D - a previously reported trade was cancelled by the exchange
+    Cancel { symbol: String, trade_id: String },
+    // * R - the trade history for the symbol was reset
+    Reset { symbol: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub symbol: String,
+    pub time: NaiveTime,
+    pub price: f64,
+    pub quantity: u64,
+    pub buyer: String,
+    pub seller: String,
+    pub trade_id: String,
+    pub aggressor: Option<Aggressor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggressor {
+    Buyer,
+    Seller,
+}
+
+// * Returns None when the line is not a GQT trade message
+pub fn parse(line: &[u8]) -> Option<Result<TradeMessage, ParseError>> {
+    message_body(line, "V").map(|body| body.and_then(parse_body))
+}
+
+fn parse_body(body: &str) -> Result<TradeMessage, ParseError> {
+    let mut fields = body.split(':');
+    let symbol = next_field(&mut fields, "symbol")?;
+    if symbol.is_empty() {
+        return Err(ParseError::Truncated("symbol"));
+    }
+    let symbol = symbol.to_string();
+
+    match next_field(&mut fields, "operation")? {
+        "A" => {
+            let time = parse_time("trade time", next_field(&mut fields, "trade time")?)?;
+            let price = parse_price("price", next_field(&mut fields, "price")?)?;
+            let buyer = next_field(&mut fields, "buyer")?.to_string();
+            let seller = next_field(&mut fields, "seller")?.to_string();
+            let quantity = parse_quantity("quantity", next_field(&mut fields, "quantity")?)?;
+            let trade_id = next_field(&mut fields, "trade id")?.to_string();
+            let _request_type = fields.next();
+            let aggressor = match fields.next() {
+                Some("A") => Some(Aggressor::Buyer),
+                Some("V") => Some(Aggressor::Seller),
+                _ => None,
+            };
+            Ok(TradeMessage::Trade(Trade {
+                symbol,
+                time,
+                price,
+                quantity,
+                buyer,
+                seller,
+                trade_id,
+                aggressor,
+            }))
+        }
+        "D" => Ok(TradeMessage::Cancel {
+            symbol,
+            trade_id: next_field(&mut fields, "trade id")?.to_string(),
+        }),
+        "R" => Ok(TradeMessage::Reset { symbol }),
+        other => Err(invalid("trade operation", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(aggressor: Option<Aggressor>) -> TradeMessage {
+        TradeMessage::Trade(Trade {
+            symbol: "PETR4".to_string(),
+            time: NaiveTime::from_hms_milli_opt(10, 30, 15, 250).unwrap(),
+            price: 38.5,
+            quantity: 300,
+            buyer: "308".to_string(),
+            seller: "120".to_string(),
+            trade_id: "98765".to_string(),
+            aggressor,
+        })
+    }
+
+    #[test]
+    fn parses_trades() {
+        assert_eq!(
+            parse(b"V:PETR4:A:103015250:38.50:308:120:300:98765:0:A!\r\n").unwrap(),
+            Ok(trade(Some(Aggressor::Buyer)))
+        );
+        assert_eq!(
+            parse(b"V:PETR4:A:103015250:38.50:308:120:300:98765:0:V!").unwrap(),
+            Ok(trade(Some(Aggressor::Seller)))
+        );
+    }
+
+    #[test]
+    fn trades_without_a_known_aggressor() {
+        let expected = Ok(trade(None));
+        assert_eq!(
+            parse(b"V:PETR4:A:103015250:38.50:308:120:300:98765!").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse(b"V:PETR4:A:103015250:38.50:308:120:300:98765:0:L:extra!").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn parses_cancel_and_reset() {
+        assert_eq!(
+            parse(b"V:PETR4:D:98765!").unwrap(),
+            Ok(TradeMessage::Cancel {
+                symbol: "PETR4".to_string(),
+                trade_id: "98765".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(b"V:PETR4:R!").unwrap(),
+            Ok(TradeMessage::Reset {
+                symbol: "PETR4".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_trades() {
+        assert!(parse(b"B:PETR4:E!").is_none());
+        assert_eq!(
+            parse(b"V:PETR4:X!").unwrap(),
+            Err(invalid("trade operation", "X"))
+        );
+        assert_eq!(
+            parse(b"V:PETR4:A:103015250:abc:308:120:300:98765!").unwrap(),
+            Err(invalid("price", "abc"))
+        );
+        assert_eq!(
+            parse(b"V:PETR4:A:103015250:38.50:308:120!").unwrap(),
+            Err(ParseError::Truncated("quantity"))
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{timeout_at, Duration, Instant};
+
+const HANDSHAKE_TIMEOUT: u64 = 30;
+const AUTH_FAILURE_MARKERS: [&str; 4] = ["invalid", "inválid", "incorrect", "failed"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginState {
+    Connecting,
+    AwaitUsername,
+    AwaitPassword,
+    AwaitConfirmation,
+    Authenticated,
+    AuthFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    Newline,
+    Username,
+    Password,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    AuthFailed(String),
+    Timeout(LoginState),
+    Closed(LoginState),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::AuthFailed(reason) => {
+                write!(f, "Error: Crystal authentication failed - {}", reason)
+            }
+            HandshakeError::Timeout(state) => {
+                write!(f, "Error: Crystal handshake timeout in state {:?}", state)
+            }
+            HandshakeError::Closed(state) => {
+                write!(
+                    f,
+                    "Error: Crystal closed the connection in state {:?}",
+                    state
+                )
+            }
+            HandshakeError::Io(e) => write!(f, "Error: Crystal handshake - {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+// * Connecting -> AwaitUsername -> AwaitPassword -> AwaitConfirmation -> Authenticated | AuthFailed
+// * Returns the bytes read after "You are connected" so no market data is lost
+pub async fn login<S>(
+    stream: &mut S,
+    username: &str,
+    password: &str,
+) -> Result<Vec<u8>, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let deadline = Instant::now() + Duration::from_secs(HANDSHAKE_TIMEOUT);
+    let mut state = LoginState::Connecting;
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+    let mut chunk = vec![0; 1024];
+
+    loop {
+        let nbytes = match timeout_at(deadline, stream.read(&mut chunk)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => return Err(HandshakeError::Io(e)),
+            Err(_) => return Err(HandshakeError::Timeout(state)),
+        };
+        if nbytes == 0 {
+            if state == LoginState::AwaitConfirmation {
+                return Err(HandshakeError::AuthFailed(
+                    "connection closed after credentials".to_string(),
+                ));
+            }
+            return Err(HandshakeError::Closed(state));
+        }
+        buffer.extend_from_slice(&chunk[..nbytes]);
+
+        let mut pos = 0;
+        while pos < buffer.len() {
+            // * Prompts may arrive without a trailing newline, so the pending tail is checked too
+            let (line_end, complete) = match buffer[pos..].iter().position(|&b| b == b'\n') {
+                Some(newline_pos) => (pos + newline_pos + 1, true),
+                None => (buffer.len(), false),
+            };
+            let line = String::from_utf8_lossy(&buffer[pos..line_end]).to_string();
+
+            let (next, reply) = transition(state, &line);
+            if next == state && !complete {
+                break;
+            }
+            pos = line_end;
+            state = next;
+
+            match reply {
+                Some(Reply::Newline) => stream.write_all(b"\n").await?,
+                Some(Reply::Username) => stream.write_all(username.as_bytes()).await?,
+                Some(Reply::Password) => stream.write_all(password.as_bytes()).await?,
+                None => {}
+            }
+
+            match state {
+                LoginState::AuthFailed => {
+                    return Err(HandshakeError::AuthFailed(line.trim().to_string()))
+                }
+                LoginState::Authenticated => return Ok(buffer.split_off(pos)),
+                _ => {}
+            }
+        }
+        buffer.drain(..pos);
+    }
+}
+
+fn transition(state: LoginState, line: &str) -> (LoginState, Option<Reply>) {
+    match state {
+        LoginState::Connecting if line.contains("Connecting...") => {
+            (LoginState::AwaitUsername, Some(Reply::Newline))
+        }
+        LoginState::Connecting | LoginState::AwaitUsername if line.contains("Username:") => {
+            (LoginState::AwaitPassword, Some(Reply::Username))
+        }
+        LoginState::AwaitPassword if line.contains("Password:") => {
+            (LoginState::AwaitConfirmation, Some(Reply::Password))
+        }
+        LoginState::AwaitConfirmation if line.contains("You are connected") => {
+            (LoginState::Authenticated, None)
+        }
+        // * Crystal asks for the username again when the credentials are rejected
+        LoginState::AwaitPassword | LoginState::AwaitConfirmation
+            if line.contains("Username:") || is_auth_failure(line) =>
+        {
+            (LoginState::AuthFailed, None)
+        }
+        _ => (state, None),
+    }
+}
+
+fn is_auth_failure(line: &str) -> bool {
+    let line = line.to_lowercase();
+    AUTH_FAILURE_MARKERS
+        .iter()
+        .any(|marker| line.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncBufReadExt, BufReader, DuplexStream};
+
+    #[test]
+    fn walks_the_login_states() {
+        let steps = [
+            (
+                LoginState::Connecting,
+                "Connecting...\r\n",
+                LoginState::AwaitUsername,
+                Some(Reply::Newline),
+            ),
+            (
+                LoginState::AwaitUsername,
+                "Username: ",
+                LoginState::AwaitPassword,
+                Some(Reply::Username),
+            ),
+            (
+                LoginState::AwaitPassword,
+                "Password: ",
+                LoginState::AwaitConfirmation,
+                Some(Reply::Password),
+            ),
+            (
+                LoginState::AwaitConfirmation,
+                "You are connected\r\n",
+                LoginState::Authenticated,
+                None,
+            ),
+        ];
+        for (state, line, next, reply) in steps {
+            assert_eq!(
+                transition(state, line),
+                (next, reply),
+                "{:?} {:?}",
+                state,
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn skips_the_connecting_banner_when_missing() {
+        assert_eq!(
+            transition(LoginState::Connecting, "Username: "),
+            (LoginState::AwaitPassword, Some(Reply::Username))
+        );
+    }
+
+    #[test]
+    fn unrelated_lines_keep_the_state() {
+        for state in [
+            LoginState::Connecting,
+            LoginState::AwaitUsername,
+            LoginState::AwaitPassword,
+            LoginState::AwaitConfirmation,
+        ] {
+            assert_eq!(transition(state, "Welcome to Crystal\r\n"), (state, None));
+        }
+    }
+
+    #[test]
+    fn detects_rejected_logins() {
+        assert_eq!(
+            transition(LoginState::AwaitConfirmation, "Username: "),
+            (LoginState::AuthFailed, None)
+        );
+        assert_eq!(
+            transition(
+                LoginState::AwaitConfirmation,
+                "Usuário ou senha inválidos\r\n"
+            ),
+            (LoginState::AuthFailed, None)
+        );
+        assert_eq!(
+            transition(LoginState::AwaitPassword, "Login FAILED\r\n"),
+            (LoginState::AuthFailed, None)
+        );
+    }
+
+    // * Plays the Crystal side of the login and returns what the client sent
+    async fn server(mut stream: DuplexStream, accept: bool) -> Vec<String> {
+        let mut received = Vec::new();
+        stream.write_all(b"Connecting...\r\n").await.unwrap();
+        let mut reader = BufReader::new(&mut stream);
+        for prompt in ["Username: ", "Password: "] {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            received.push(line);
+            reader.get_mut().write_all(prompt.as_bytes()).await.unwrap();
+        }
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        received.push(line);
+        let reply: &[u8] = if accept {
+            b"You are connected\r\nT:PETR4:103015:2:38.50!\r\n"
+        } else {
+            b"Invalid username or password\r\n"
+        };
+        reader.get_mut().write_all(reply).await.unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn logs_in_and_keeps_the_data_after_the_confirmation() {
+        let (mut client, crystal) = duplex(1024);
+        let server = tokio::spawn(server(crystal, true));
+        let leftover = login(&mut client, "user\n", "secret\n").await.unwrap();
+        assert_eq!(leftover, b"T:PETR4:103015:2:38.50!\r\n");
+        assert_eq!(server.await.unwrap(), vec!["\n", "user\n", "secret\n"]);
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_credentials() {
+        let (mut client, crystal) = duplex(1024);
+        let server = tokio::spawn(server(crystal, false));
+        let error = login(&mut client, "user\n", "wrong\n").await.unwrap_err();
+        assert!(
+            matches!(&error, HandshakeError::AuthFailed(reason) if reason == "Invalid username or password"),
+            "{}",
+            error
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_the_state_when_closed() {
+        let (mut client, mut crystal) = duplex(1024);
+        crystal.write_all(b"Welcome\r\n").await.unwrap();
+        drop(crystal);
+        let error = login(&mut client, "user\n", "secret\n").await.unwrap_err();
+        assert!(
+            matches!(error, HandshakeError::Closed(LoginState::Connecting)),
+            "{}",
+            error
+        );
+    }
+}
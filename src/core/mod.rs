@@ -1,4 +1,27 @@
+pub mod admin;
 pub mod app;
+pub mod bqt;
+pub mod budget;
+pub mod capture;
+pub mod compression;
 pub mod crystal;
 pub mod crystal_params;
+pub mod endpoints;
+pub mod events;
 pub mod futures;
+pub mod gqt;
+pub mod handshake;
+pub mod order_book;
+pub mod parse;
+pub mod pipeline;
+pub mod recovery;
+pub mod replay;
+pub mod segments;
+pub mod server;
+pub mod spool;
+pub mod sqt;
+pub mod stats;
+pub mod subscriptions;
+pub mod transport;
+pub mod watchdog;
+pub mod writer;
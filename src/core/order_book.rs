@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::bqt::{BookEvent, BookMessage, DeleteKind, Order, Side};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    pub price: f64,
+    pub quantity: u64,
+    pub orders: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookError {
+    PositionOutOfRange {
+        symbol: String,
+        side: Side,
+        position: usize,
+        len: usize,
+    },
+}
+
+impl fmt::Display for BookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BookError::PositionOutOfRange {
+                symbol,
+                side,
+                position,
+                len,
+            } => write!(
+                f,
+                "Error: book {} {:?} position {} out of range ({} orders)",
+                symbol, side, position, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BookError {}
+
+// * Orders are kept in Crystal priority order, position 0 is the top of the book
+#[derive(Debug, Default, Clone)]
+pub struct OrderBook {
+    bids: Vec<Order>,
+    asks: Vec<Order>,
+    synced: bool,
+}
+
+impl OrderBook {
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    pub fn top(&self, side: Side, depth: usize) -> Vec<Level> {
+        let mut levels: Vec<Level> = Vec::with_capacity(depth);
+        for order in self.side(side) {
+            match levels.last_mut() {
+                Some(level) if level.price == order.price => {
+                    level.quantity += order.quantity;
+                    level.orders += 1;
+                }
+                _ => {
+                    if levels.len() == depth {
+                        break;
+                    }
+                    levels.push(Level {
+                        price: order.price,
+                        quantity: order.quantity,
+                        orders: 1,
+                    });
+                }
+            }
+        }
+        levels
+    }
+
+    fn side(&self, side: Side) -> &Vec<Order> {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut Vec<Order> {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+
+    fn apply(&mut self, symbol: &str, event: &BookEvent) -> Result<(), BookError> {
+        let out_of_range =
+            |side: Side, position: usize, len: usize| BookError::PositionOutOfRange {
+                symbol: symbol.to_string(),
+                side,
+                position,
+                len,
+            };
+
+        match event {
+            BookEvent::Add { position, order } => {
+                let orders = self.side_mut(order.side);
+                if *position > orders.len() {
+                    return Err(out_of_range(order.side, *position, orders.len()));
+                }
+                orders.insert(*position, order.clone());
+            }
+            BookEvent::Update {
+                position,
+                old_position,
+                order,
+            } => {
+                let orders = self.side_mut(order.side);
+                if *old_position >= orders.len() {
+                    return Err(out_of_range(order.side, *old_position, orders.len()));
+                }
+                orders.remove(*old_position);
+                if *position > orders.len() {
+                    return Err(out_of_range(order.side, *position, orders.len()));
+                }
+                orders.insert(*position, order.clone());
+            }
+            BookEvent::Delete {
+                kind,
+                side,
+                position,
+            } => {
+                let orders = self.side_mut(*side);
+                match kind {
+                    DeleteKind::Single => {
+                        if *position >= orders.len() {
+                            return Err(out_of_range(*side, *position, orders.len()));
+                        }
+                        orders.remove(*position);
+                    }
+                    DeleteKind::UpTo => {
+                        let end = (*position + 1).min(orders.len());
+                        orders.drain(..end);
+                    }
+                    DeleteKind::All => orders.clear(),
+                }
+            }
+            BookEvent::Snapshot => self.synced = true,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OrderBooks {
+    books: HashMap<String, OrderBook>,
+}
+
+impl OrderBooks {
+    // * A rejected event marks the book out of sync until Crystal sends a new snapshot
+    pub fn apply(&mut self, message: &BookMessage) -> Result<(), BookError> {
+        let book = self.books.entry(message.symbol.clone()).or_default();
+        let result = book.apply(&message.symbol, &message.event);
+        if result.is_err() {
+            book.synced = false;
+        }
+        result
+    }
+
+    pub fn top(&self, symbol: &str, side: Side, depth: usize) -> Vec<Level> {
+        self.books
+            .get(symbol)
+            .map(|book| book.top(side, depth))
+            .unwrap_or_default()
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = &String> {
+        self.books.keys()
+    }
+
+    // * Format: <time> <symbol> <BID|ASK> <synced|unsynced> <price>:<quantity>:<orders>...
+    pub fn format_snapshot(&self, time: &str, depth: usize) -> String {
+        let mut symbols: Vec<&String> = self.symbols().collect();
+        symbols.sort();
+
+        let mut snapshot = String::new();
+        for symbol in symbols {
+            let state = if self.books[symbol].is_synced() {
+                "synced"
+            } else {
+                "unsynced"
+            };
+            for (side, label) in [(Side::Bid, "BID"), (Side::Ask, "ASK")] {
+                snapshot.push_str(&format!("{} {} {} {}", time, symbol, label, state));
+                for level in self.top(symbol, side, depth) {
+                    snapshot.push_str(&format!(
+                        " {}:{}:{}",
+                        level.price, level.quantity, level.orders
+                    ));
+                }
+                snapshot.push('\n');
+            }
+        }
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::bqt;
+
+    fn apply(books: &mut OrderBooks, line: &str) -> Result<(), BookError> {
+        books.apply(&bqt::parse(line.as_bytes()).unwrap().unwrap())
+    }
+
+    fn level(price: f64, quantity: u64, orders: usize) -> Level {
+        Level {
+            price,
+            quantity,
+            orders,
+        }
+    }
+
+    // * Bids 38.50x100, 38.50x200, 38.49x300 and asks 38.51x400, 38.52x500
+    fn snapshot() -> OrderBooks {
+        let mut books = OrderBooks::default();
+        for line in [
+            "B:PETR4:A:0:A:38.49:300:308:20240502103000:1:1!",
+            "B:PETR4:A:0:A:38.50:200:308:20240502103001:2:1!",
+            "B:PETR4:A:0:A:38.50:100:308:20240502103002:3:1!",
+            "B:PETR4:A:0:V:38.52:500:308:20240502103003:4:1!",
+            "B:PETR4:A:0:V:38.51:400:308:20240502103004:5:1!",
+            "B:PETR4:E!",
+        ] {
+            apply(&mut books, line).unwrap();
+        }
+        books
+    }
+
+    #[test]
+    fn aggregates_the_top_of_book_by_price() {
+        let books = snapshot();
+        assert_eq!(
+            books.top("PETR4", Side::Bid, 5),
+            vec![level(38.5, 300, 2), level(38.49, 300, 1)]
+        );
+        assert_eq!(books.top("PETR4", Side::Ask, 1), vec![level(38.51, 400, 1)]);
+        assert!(books.top("VALE3", Side::Bid, 5).is_empty());
+    }
+
+    #[test]
+    fn updates_move_orders() {
+        let mut books = snapshot();
+        apply(
+            &mut books,
+            "B:PETR4:U:2:0:A:38.49:150:308:20240502103005:3:1!",
+        )
+        .unwrap();
+        assert_eq!(
+            books.top("PETR4", Side::Bid, 5),
+            vec![level(38.5, 200, 1), level(38.49, 450, 2)]
+        );
+    }
+
+    #[test]
+    fn deletes_single_up_to_and_all() {
+        let mut books = snapshot();
+        apply(&mut books, "B:PETR4:D:1:A:2!").unwrap();
+        assert_eq!(books.top("PETR4", Side::Bid, 5), vec![level(38.5, 300, 2)]);
+
+        apply(&mut books, "B:PETR4:D:2:A:0!").unwrap();
+        assert_eq!(books.top("PETR4", Side::Bid, 5), vec![level(38.5, 200, 1)]);
+
+        apply(&mut books, "B:PETR4:D:3:V!").unwrap();
+        assert!(books.top("PETR4", Side::Ask, 5).is_empty());
+        assert_eq!(books.top("PETR4", Side::Bid, 5), vec![level(38.5, 200, 1)]);
+    }
+
+    #[test]
+    fn out_of_range_marks_the_book_unsynced() {
+        let mut books = snapshot();
+        assert!(books
+            .format_snapshot("10:30:05", 1)
+            .contains("PETR4 BID synced"));
+
+        let error = apply(&mut books, "B:PETR4:D:1:V:5!").unwrap_err();
+        assert_eq!(
+            error,
+            BookError::PositionOutOfRange {
+                symbol: "PETR4".to_string(),
+                side: Side::Ask,
+                position: 5,
+                len: 2,
+            }
+        );
+        assert!(books
+            .format_snapshot("10:30:05", 1)
+            .contains("PETR4 BID unsynced"));
+
+        apply(&mut books, "B:PETR4:E!").unwrap();
+        assert!(books
+            .format_snapshot("10:30:05", 1)
+            .contains("PETR4 BID synced"));
+    }
+
+    #[test]
+    fn formats_snapshots() {
+        assert_eq!(
+            snapshot().format_snapshot("10:30:05", 2),
+            "10:30:05 PETR4 BID synced 38.5:300:2 38.49:300:1\n\
+             10:30:05 PETR4 ASK synced 38.51:400:1 38.52:500:1\n"
+        );
+    }
+}
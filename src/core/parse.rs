@@ -0,0 +1,108 @@
+use chrono::{NaiveDate, NaiveTime};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Encoding,
+    Truncated(&'static str),
+    InvalidField { field: &'static str, value: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Encoding => write!(f, "Error: message is not valid UTF-8"),
+            ParseError::Truncated(field) => write!(f, "Error: message truncated at {}", field),
+            ParseError::InvalidField { field, value } => {
+                write!(f, "Error: invalid {} - {:?}", field, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// * Crystal messages look like `<tag>:<field>:<field>...!` followed by CRLF
+pub fn message_body<'a>(line: &'a [u8], tag: &str) -> Option<Result<&'a str, ParseError>> {
+    let line = trim_line_end(line);
+    if line.len() < tag.len() + 1 || &line[..tag.len()] != tag.as_bytes() || line[tag.len()] != b':'
+    {
+        return None;
+    }
+    let body = &line[tag.len() + 1..];
+    let body = body.strip_suffix(b"!").unwrap_or(body);
+    Some(std::str::from_utf8(body).map_err(|_| ParseError::Encoding))
+}
+
+pub fn trim_line_end(line: &[u8]) -> &[u8] {
+    let mut end = line.len();
+    while end > 0 && (line[end - 1] == b'\n' || line[end - 1] == b'\r') {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+pub fn next_field<'a>(
+    fields: &mut impl Iterator<Item = &'a str>,
+    name: &'static str,
+) -> Result<&'a str, ParseError> {
+    fields.next().ok_or(ParseError::Truncated(name))
+}
+
+pub fn invalid(field: &'static str, value: &str) -> ParseError {
+    ParseError::InvalidField {
+        field,
+        value: value.to_string(),
+    }
+}
+
+pub fn parse_price(field: &'static str, value: &str) -> Result<f64, ParseError> {
+    value.parse::<f64>().map_err(|_| invalid(field, value))
+}
+
+pub fn parse_quantity(field: &'static str, value: &str) -> Result<u64, ParseError> {
+    value.parse::<u64>().map_err(|_| invalid(field, value))
+}
+
+// * Format: HHMMSS or HHMMSSmmm
+pub fn parse_time(field: &'static str, value: &str) -> Result<NaiveTime, ParseError> {
+    let format = match value.len() {
+        6 => "%H%M%S",
+        9 => "%H%M%S%3f",
+        _ => return Err(invalid(field, value)),
+    };
+    NaiveTime::parse_from_str(value, format).map_err(|_| invalid(field, value))
+}
+
+// * Format: YYYYMMDD
+pub fn parse_date(field: &'static str, value: &str) -> Result<NaiveDate, ParseError> {
+    NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| invalid(field, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_body_after_the_tag() {
+        assert_eq!(message_body(b"V:PETR4:R!\r\n", "V"), Some(Ok("PETR4:R")));
+        assert_eq!(message_body(b"V:PETR4:R!\r\n", "T"), None);
+        // * Capture headers are removed by the replay, live lines start with the tag
+        assert_eq!(message_body(b"10:30:15.300 V:PETR4:R!\r\n", "V"), None);
+        assert_eq!(message_body(b"You are connected", "Y"), None);
+    }
+
+    #[test]
+    fn parses_times_with_and_without_milliseconds() {
+        assert_eq!(
+            parse_time("time", "103015"),
+            Ok(NaiveTime::from_hms_opt(10, 30, 15).unwrap())
+        );
+        assert_eq!(
+            parse_time("time", "103015250"),
+            Ok(NaiveTime::from_hms_milli_opt(10, 30, 15, 250).unwrap())
+        );
+        assert_eq!(parse_time("time", "1030"), Err(invalid("time", "1030")));
+        assert_eq!(parse_time("time", "253015"), Err(invalid("time", "253015")));
+    }
+}
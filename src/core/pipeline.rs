@@ -0,0 +1,72 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::bqt;
+use super::events::MarketEvent;
+use super::gqt;
+use super::order_book::OrderBooks;
+use super::sqt;
+use super::stats::CaptureStats;
+use super::subscriptions::{CrystalError, Subscriptions};
+
+// * Everything done with a Crystal line once it is captured, shared by live sessions and replay
+pub struct LineProcessor {
+    pub subscriptions: Arc<StdMutex<Subscriptions>>,
+    pub books: Arc<Mutex<OrderBooks>>,
+    pub stats: Arc<CaptureStats>,
+}
+
+impl LineProcessor {
+    // * Returns the normalized quote, book or trade event carried by the line
+    pub async fn process(&self, line: &[u8]) -> Option<MarketEvent> {
+        match CrystalError::parse(line) {
+            Some(Ok(error)) => match self.subscriptions.lock().unwrap().reject(&error) {
+                Some((symbol, feed)) => {
+                    warn!(symbol = %symbol, feed = %feed, reason = error.reason(), "Rejected")
+                }
+                None => {
+                    if error.symbol.is_none() {
+                        self.stats
+                            .unattributed_errors
+                            .fetch_add(1, Ordering::SeqCst);
+                    }
+                    warn!(reason = error.reason(), "Crystal error")
+                }
+            },
+            Some(Err(e)) => warn!(line = %String::from_utf8_lossy(line).trim_end(), "{}", e),
+            None => self.subscriptions.lock().unwrap().acknowledge(line),
+        }
+
+        match sqt::parse(line) {
+            Some(Ok(update)) => return Some(MarketEvent::Quote(update)),
+            Some(Err(e)) => {
+                self.stats.malformed_quotes.fetch_add(1, Ordering::SeqCst);
+                warn!(line = %String::from_utf8_lossy(line).trim_end(), "{}", e);
+            }
+            None => {}
+        }
+
+        match bqt::parse(line) {
+            Some(Ok(message)) => {
+                if let Err(e) = self.books.lock().await.apply(&message) {
+                    warn!("{}", e);
+                }
+                return Some(MarketEvent::Book(message));
+            }
+            Some(Err(e)) => warn!(line = %String::from_utf8_lossy(line).trim_end(), "{}", e),
+            None => {}
+        }
+
+        match gqt::parse(line) {
+            Some(Ok(message)) => return Some(MarketEvent::Trade(message)),
+            Some(Err(e)) => {
+                self.stats.malformed_trades.fetch_add(1, Ordering::SeqCst);
+                warn!(line = %String::from_utf8_lossy(line).trim_end(), "{}", e);
+            }
+            None => {}
+        }
+        None
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use sentry::Level;
+use std::error::Error;
+use std::fs::{create_dir_all, read_dir, read_to_string, rename, write};
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::helpers::config::{vault_url, BLOB_ACCOUNT, BLOB_CONTAINER, BLOB_KEY};
+use crate::helpers::errors::report_error;
+use crate::helpers::storage;
+use crate::helpers::vault;
+
+const CONTENT_DIR: &str = "content";
+const RECOVERY_DIR: &str = "recovery";
+const SESSION_MARKER: &str = ".session";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+// * Held while recovery folders upload, a slow upload is not started twice
+static UPLOADING: Mutex<()> = Mutex::const_new(());
+
+// * What set_aside moved out of content/
+struct Leftover {
+    dir: PathBuf,
+    files: usize,
+    date: NaiveDate,
+}
+
+// * Format: content/.session holds the UTC date of the session writing to content/
+pub fn mark_session() {
+    let marker = Path::new(CONTENT_DIR).join(SESSION_MARKER);
+    if let Err(e) = write(&marker, Utc::now().format(DATE_FORMAT).to_string()) {
+        error!(file = %marker.display(), error = ?e, "Write session marker");
+    }
+}
+
+// * Moves whatever a crashed session left in content/ to recovery/<date>-<HHMMSS>/ so the new
+// * session starts empty, then uploads every recovery folder under its original date in the
+// * background. Folders that fail to upload stay in recovery/ for the next start
+pub fn recover() {
+    match set_aside() {
+        Ok(Some(leftover)) => {
+            let message = format!(
+                "CMDC - RECOVERY {} files from {} moved to {}",
+                leftover.files,
+                leftover.date,
+                leftover.dir.display()
+            );
+            warn!(
+                files = leftover.files,
+                date = %leftover.date,
+                dir = %leftover.dir.display(),
+                "Leftover capture set aside"
+            );
+            sentry::capture_message(&message, Level::Warning);
+        }
+        Ok(None) => {}
+        Err(e) => report_error(&format!("Error: set aside leftover capture - {}", e)),
+    }
+
+    tokio::spawn(upload_all());
+}
+
+async fn upload_all() {
+    let Ok(_uploading) = UPLOADING.try_lock() else {
+        info!("Recovery upload already running");
+        return;
+    };
+    let Ok(entries) = read_dir(RECOVERY_DIR) else {
+        return;
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    for dir in dirs {
+        match upload(&dir).await {
+            Ok(name) => {
+                let message = format!("CMDC - RECOVERY uploaded {}", name);
+                info!(name = %name, "Leftover capture uploaded");
+                sentry::capture_message(&message, Level::Info);
+            }
+            Err(e) => report_error(&format!(
+                "Error: upload leftover capture {} - {}",
+                dir.display(),
+                e
+            )),
+        }
+    }
+}
+
+fn set_aside() -> Result<Option<Leftover>, Box<dyn Error>> {
+    let content = Path::new(CONTENT_DIR);
+    if !content.exists() {
+        return Ok(None);
+    }
+    let leftover: Vec<PathBuf> = read_dir(content)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    if leftover
+        .iter()
+        .all(|path| path.file_name().is_some_and(|name| name == SESSION_MARKER))
+    {
+        return Ok(None);
+    }
+
+    let date = session_date(content, &leftover);
+    let dir = Path::new(RECOVERY_DIR).join(format!(
+        "{}-{}",
+        date.format(DATE_FORMAT),
+        Local::now().format("%H%M%S")
+    ));
+    create_dir_all(&dir)?;
+    let mut files = 0;
+    for path in leftover {
+        // * The marker stays in content/ for mark_session to overwrite, it is not capture
+        if let Some(name) = path.file_name().filter(|name| *name != SESSION_MARKER) {
+            rename(&path, dir.join(name))?;
+            files += 1;
+        }
+    }
+    Ok(Some(Leftover { dir, files, date }))
+}
+
+// * The marker date, or the UTC date of the newest file for captures from before the marker
+fn session_date(content: &Path, leftover: &[PathBuf]) -> NaiveDate {
+    let marker = read_to_string(content.join(SESSION_MARKER))
+        .ok()
+        .and_then(|date| NaiveDate::parse_from_str(date.trim(), DATE_FORMAT).ok());
+    if let Some(date) = marker {
+        return date;
+    }
+    leftover
+        .iter()
+        .filter_map(|path| {
+            path.metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .max()
+        .map(|modified| DateTime::<Utc>::from(modified).date_naive())
+        .unwrap_or_else(|| Utc::now().date_naive())
+}
+
+// * Format: <date>/md-<date>-recovered-<HHMMSS>.zip
+async fn upload(dir: &Path) -> Result<String, Box<dyn Error>> {
+    let dir_name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Error: recovery folder name")?;
+    let (date, time) = dir_name
+        .rsplit_once('-')
+        .ok_or("Error: recovery folder name")?;
+    NaiveDate::parse_from_str(date, DATE_FORMAT)?;
+    let name = format!("md-{}-recovered-{}", date, time);
+    let path = dir.to_str().ok_or("Error: recovery folder path")?;
+
+    let account = vault::get_secret(BLOB_ACCOUNT, &vault_url()).await?;
+    let container = vault::get_secret(BLOB_CONTAINER, &vault_url()).await?;
+    let key = vault::get_secret(BLOB_KEY, &vault_url()).await?;
+    storage::upload_folder_to_blob(&account, &container, path, &name, date, &key).await?;
+    Ok(name)
+}
@@ -0,0 +1,223 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeDelta, Utc};
+use std::error::Error;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep_until, Instant};
+use tracing::{info, warn};
+
+use super::budget::MemoryBudget;
+use super::capture::{self, CaptureReader, CapturedLine, LineHeader, Order, TIMESTAMP_FORMAT};
+use super::crystal::{BOOK_DEPTH, BOOK_SNAPSHOT_INTERVAL};
+use super::events::{self, EventMessage, EventRecord, EVENT_SIZE, MAX_EVENT_BUFFER};
+use super::order_book::OrderBooks;
+use super::pipeline::LineProcessor;
+use super::stats::CaptureStats;
+use super::subscriptions::Subscriptions;
+use crate::helpers::config::{buffer_bytes, parquet_rows};
+use crate::helpers::unzip;
+
+const REPLAY_DIR: &str = "replay";
+const CHANNEL_SIZE: usize = 10000;
+const PROGRESS_INTERVAL: usize = 1000000;
+
+// * Format: realtime | max | <factor>, e.g. 10 replays ten times faster than recorded
+fn parse_speed(speed: &str) -> Result<Option<f64>, Box<dyn Error>> {
+    match speed.to_lowercase().as_str() {
+        "realtime" => Ok(Some(1.0)),
+        "max" | "fast" => Ok(None),
+        factor => match factor.parse::<f64>() {
+            Ok(factor) if factor > 0.0 => Ok(Some(factor)),
+            _ => Err(format!("Error: invalid replay speed {}", speed).into()),
+        },
+    }
+}
+
+// * Accepts the content folder, a single crystal-md file or the uploaded md-YYYY-MM-DD.zip
+fn resolve_input(path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if path.is_dir() {
+        return Ok(capture::capture_files(path)?);
+    }
+    if path.extension().is_some_and(|extension| extension == "zip") {
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or("Error: invalid capture file name")?;
+        let dest = Path::new(REPLAY_DIR).join(stem);
+        create_dir_all(&dest)?;
+        info!(path = %path.display(), dest = %dest.display(), "Unzipping");
+        unzip::unzip_path(path, &dest);
+        return Ok(capture::capture_files(&dest)?);
+    }
+    Ok(vec![path.to_path_buf()])
+}
+
+// * Format: md-YYYY-MM-DD[...].zip, otherwise the local date the first file was last written
+fn capture_date(path: &Path, files: &[PathBuf]) -> NaiveDate {
+    let named = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.strip_prefix("md-"))
+        .and_then(|stem| stem.get(..10))
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+    if let Some(date) = named {
+        return date;
+    }
+    files
+        .first()
+        .and_then(|file| {
+            file.metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .map(|modified| DateTime::<Local>::from(modified).date_naive())
+        .unwrap_or_else(|| Local::now().date_naive())
+}
+
+// * Full format lines carry the UTC arrival, the other formats only the local time of day
+fn received_at(header: &LineHeader, date: NaiveDate) -> DateTime<Utc> {
+    match header.date {
+        Some(date) => date.and_time(header.time).and_utc(),
+        None => date
+            .and_time(header.time)
+            .and_local_timezone(Local)
+            .earliest()
+            .map_or_else(
+                || date.and_time(header.time).and_utc(),
+                |time| time.with_timezone(&Utc),
+            ),
+    }
+}
+
+pub async fn run(path: &str, speed: &str) -> Result<(), Box<dyn Error>> {
+    let speed = parse_speed(speed)?;
+    let files = resolve_input(Path::new(path))?;
+    if files.is_empty() {
+        return Err(format!("Error: no crystal-md files in {}", path).into());
+    }
+    let date = capture_date(Path::new(path), &files);
+
+    create_dir_all(REPLAY_DIR)?;
+    let books_path = Path::new(REPLAY_DIR).join("crystal-books.txt");
+    let mut books_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&books_path)?;
+
+    // * Reading and merging the files is blocking, the pipeline consumes it through a channel
+    let (tx, mut rx) = mpsc::channel::<CapturedLine>(CHANNEL_SIZE);
+    let reader = tokio::task::spawn_blocking(move || -> Result<(), String> {
+        let reader = CaptureReader::open(&files, Order::Time).map_err(|e| e.to_string())?;
+        for line in reader {
+            let line = line.map_err(|e| e.to_string())?;
+            if tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let processor = LineProcessor {
+        subscriptions: Arc::new(StdMutex::new(Subscriptions::default())),
+        books: Arc::new(Mutex::new(OrderBooks::default())),
+        stats: Arc::new(CaptureStats::default()),
+    };
+    let snapshot_interval = TimeDelta::seconds(BOOK_SNAPSHOT_INTERVAL as i64);
+
+    // * Events go to replay/ as they would to content/ with CHITA_PARQUET_ROWS on a live session
+    let rows = parquet_rows();
+    let (mut events, event_writer) = if rows > 0 {
+        let (tx, rx) = mpsc::channel::<EventMessage>(MAX_EVENT_BUFFER);
+        let started = Local::now();
+        let writer = tokio::task::spawn_blocking(move || {
+            events::run_event_writer(rx, PathBuf::from(REPLAY_DIR), rows, started)
+        });
+        (Some(tx), Some(writer))
+    } else {
+        (None, None)
+    };
+    let budget = MemoryBudget::new(buffer_bytes(), Arc::clone(&processor.stats));
+
+    let started = Instant::now();
+    let mut first: Option<NaiveTime> = None;
+    let mut next_snapshot: Option<NaiveTime> = None;
+    let mut lines: usize = 0;
+
+    while let Some(captured) = rx.recv().await {
+        let time = captured.header.time;
+        let first = *first.get_or_insert(time);
+        let recorded = (time - first).to_std().unwrap_or_default();
+        if let Some(factor) = speed {
+            sleep_until(started + recorded.div_f64(factor)).await;
+        }
+
+        // * Snapshots follow the recorded clock so they line up with the live ones
+        let next = *next_snapshot.get_or_insert(time + snapshot_interval);
+        if time >= next {
+            let snapshot = processor
+                .books
+                .lock()
+                .await
+                .format_snapshot(&time.format(TIMESTAMP_FORMAT).to_string(), BOOK_DEPTH);
+            books_file.write_all(snapshot.as_bytes())?;
+            next_snapshot = Some(time + snapshot_interval);
+        }
+
+        let event = processor.process(captured.line()).await;
+        lines += 1;
+        if let (Some(event), Some(tx)) = (event, &events) {
+            let record = EventRecord {
+                seq: captured.header.seq.unwrap_or(lines as u64),
+                received: received_at(&captured.header, date),
+                event,
+            };
+            // * Waits for the Parquet writer instead of dropping, a replay has no deadline
+            let reservation = budget.reserve(EVENT_SIZE).await;
+            if tx
+                .send(EventMessage::Record(record, reservation))
+                .await
+                .is_err()
+            {
+                warn!("Parquet writer stopped, events are no longer written");
+                events = None;
+            }
+        }
+        if lines.is_multiple_of(PROGRESS_INTERVAL) {
+            info!(lines, time = %time, "Replay progress");
+        }
+    }
+
+    match reader.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Err(format!("Error: read capture - {}", e).into()),
+        Err(e) => return Err(format!("Error: capture reader task - {:?}", e).into()),
+    }
+
+    // * The writer closes the last file once the channel is closed
+    drop(events);
+    if let Some(writer) = event_writer {
+        if let Err(e) = writer.await {
+            return Err(format!("Error: parquet writer task - {:?}", e).into());
+        }
+        info!(dir = REPLAY_DIR, "Parquet events written");
+    }
+
+    let snapshot = processor
+        .books
+        .lock()
+        .await
+        .format_snapshot("end", BOOK_DEPTH);
+    books_file.write_all(snapshot.as_bytes())?;
+
+    info!(
+        lines,
+        elapsed = ?started.elapsed(),
+        "Replay finished - {}",
+        processor.stats.report()
+    );
+    info!(path = %books_path.display(), "Book snapshots written");
+    Ok(())
+}
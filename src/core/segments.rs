@@ -0,0 +1,158 @@
+use chrono::{DateTime, Local, Timelike, Utc};
+use std::error::Error;
+use std::fs::{create_dir_all, read_dir, rename};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex as StdMutex;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use super::capture;
+use super::compression::Compression;
+use super::writer::WriterPool;
+use crate::helpers::config::{vault_url, BLOB_ACCOUNT, BLOB_CONTAINER, BLOB_KEY};
+use crate::helpers::storage;
+use crate::helpers::vault;
+
+const CONTENT_DIR: &str = "content";
+const SEGMENT_PREFIX: &str = "segment-";
+
+// * Session start and number of the last closed segment, the files left at stop are the next one
+static LAST_SEGMENT: StdMutex<Option<(DateTime<Local>, usize)>> = StdMutex::new(None);
+
+// * Format: content/segment-<session start HHMMSS>-<NNNN>
+fn segment_dir(started: &DateTime<Local>, segment: usize) -> PathBuf {
+    Path::new(CONTENT_DIR).join(format!(
+        "{}{}-{:04}",
+        SEGMENT_PREFIX,
+        started.format("%H%M%S"),
+        segment
+    ))
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+// * Format: md-<YYYY-MM-DD>-segment-<session start HHMMSS>-<NNNN>, ordered by name
+fn blob_name(dir_name: &str) -> String {
+    format!("md-{}-{}", today(), dir_name)
+}
+
+pub fn move_into(path: &Path, dir: &Path) {
+    let Some(name) = path.file_name() else {
+        return;
+    };
+    if !path.exists() {
+        return;
+    }
+    if let Err(e) = rename(path, dir.join(name)) {
+        error!(
+            path = %path.display(),
+            dir = %dir.display(),
+            error = ?e,
+            "Move into segment"
+        );
+    }
+}
+
+// * Closes the capture files on every multiple of the interval since midnight and uploads them
+// * in the background, the session tracker keeps stop() waiting for the uploads in flight
+pub async fn run_rotation(
+    pool: WriterPool,
+    interval: u64,
+    compression: Compression,
+    started: DateTime<Local>,
+    cancel: CancellationToken,
+    tracker: TaskTracker,
+) {
+    let mut segment = 0;
+    loop {
+        let now = Local::now();
+        let elapsed = now.num_seconds_from_midnight() as u64;
+        let wait = Duration::from_secs(interval - elapsed % interval)
+            .saturating_sub(Duration::from_nanos(now.nanosecond() as u64));
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = sleep(wait) => {}
+        }
+
+        segment += 1;
+        let dir = segment_dir(&started, segment);
+        if let Err(e) = create_dir_all(&dir) {
+            error!(dir = %dir.display(), error = ?e, "Create segment folder");
+            segment -= 1;
+            continue;
+        }
+        pool.rotate(&dir).await;
+        *LAST_SEGMENT.lock().unwrap() = Some((started, segment));
+        info!(dir = %dir.display(), "Rotated capture");
+        tracker.spawn(
+            finish_segment(dir, compression, started).instrument(info_span!("segment", segment)),
+        );
+    }
+}
+
+async fn finish_segment(dir: PathBuf, compression: Compression, started: DateTime<Local>) {
+    let output = capture::session_file(&dir, &started, compression);
+    let folder = dir.clone();
+    match tokio::task::spawn_blocking(move || capture::merge_session(&folder, &output, compression))
+        .await
+    {
+        Ok(Ok(lines)) => info!(lines, dir = %dir.display(), "Merged segment"),
+        Ok(Err(e)) => error!(dir = %dir.display(), error = ?e, "Merge segment"),
+        Err(e) => error!(error = ?e, "Merge task failed"),
+    }
+    if let Err(e) = upload_segment(&dir).await {
+        warn!(dir = %dir.display(), error = %e, "Upload segment, retrying at stop");
+    }
+}
+
+async fn upload_segment(dir: &Path) -> Result<(), Box<dyn Error>> {
+    let dir_name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Error: segment folder name")?;
+    let path = dir.to_str().ok_or("Error: segment folder path")?;
+    upload(path, &blob_name(dir_name)).await
+}
+
+async fn upload(path: &str, name: &str) -> Result<(), Box<dyn Error>> {
+    let account = vault::get_secret(BLOB_ACCOUNT, &vault_url()).await?;
+    let container = vault::get_secret(BLOB_CONTAINER, &vault_url()).await?;
+    let key = vault::get_secret(BLOB_KEY, &vault_url()).await?;
+    storage::upload_folder_to_blob(&account, &container, path, name, &today(), &key).await
+}
+
+// * Segments whose upload failed during the day, in order
+pub async fn upload_pending() {
+    let Ok(entries) = read_dir(CONTENT_DIR) else {
+        return;
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(SEGMENT_PREFIX))
+        })
+        .collect();
+    dirs.sort();
+    for dir in dirs {
+        if let Err(e) = upload_segment(&dir).await {
+            error!(dir = %dir.display(), error = %e, "Upload segment");
+        }
+    }
+}
+
+// * Blob name for the files left at stop when the session was rotated, None keeps md-<date>
+pub fn final_name() -> Option<String> {
+    let (started, segment) = LAST_SEGMENT.lock().unwrap().take()?;
+    let dir = segment_dir(&started, segment + 1);
+    dir.file_name()
+        .and_then(|name| name.to_str())
+        .map(blob_name)
+}
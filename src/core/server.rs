@@ -0,0 +1,81 @@
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use tokio::net::TcpListener;
+use tracing::info;
+
+use super::crystal;
+use super::subscriptions::{SubscriptionStatus, ALL_FEEDS};
+use crate::helpers::errors::report_error;
+use crate::helpers::health;
+use crate::helpers::metrics::METRICS;
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// * Serves /metrics, /healthz and /readyz for the whole life of the process, an empty address disables it
+pub async fn serve(addr: String) {
+    if addr.is_empty() {
+        return;
+    }
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            report_error(&format!("Error: bind HTTP server {} - {:?}", addr, e));
+            return;
+        }
+    };
+    info!(addr = %addr, "Serving metrics and health");
+
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+    if let Err(e) = axum::serve(listener, app).await {
+        report_error(&format!("Error: HTTP server {} - {:?}", addr, e));
+    }
+}
+
+async fn metrics() -> impl IntoResponse {
+    count_subscriptions();
+    ([(CONTENT_TYPE, METRICS_CONTENT_TYPE)], METRICS.encode())
+}
+
+// * 503 when the scheduler stopped ticking or no byte arrived for CHITA_HEALTH_STALL seconds
+async fn healthz() -> impl IntoResponse {
+    let report = health::report();
+    (status(report.live), Json(report.body))
+}
+
+// * 503 while connecting or unhealthy
+async fn readyz() -> impl IntoResponse {
+    let report = health::report();
+    (status(report.ready), Json(report.body))
+}
+
+fn status(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+// * Counted on every scrape, all zero while no session is running
+fn count_subscriptions() {
+    let list = crystal::subscriptions().unwrap_or_default();
+    for feed in ALL_FEEDS {
+        let active = list
+            .iter()
+            .flat_map(|(_, feeds)| feeds)
+            .filter(|(subscribed, status)| {
+                *subscribed == feed && *status == SubscriptionStatus::Active
+            })
+            .count();
+        METRICS
+            .active_subscriptions
+            .with_label_values(&[&feed.to_string()])
+            .set(active as i64);
+    }
+}
@@ -0,0 +1,333 @@
+use chrono::{DateTime, NaiveTime, Timelike};
+use std::io::{self, ErrorKind, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{remove_file, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::error;
+
+use super::capture::Stamp;
+use super::writer::{Batch, Entry};
+
+const HEADER_LEN: u64 = 12;
+// * Read batches are dropped from the file once they take this much space
+const COMPACT_BYTES: u64 = 64 * 1024 * 1024;
+const COPY_CHUNK: usize = 1024 * 1024;
+
+// * Batches that did not fit in the memory budget, kept on disk first in first out.
+// * Format: <payload length u64><writer u32> then per line
+// * <seq u64><received nanos i64><monotonic nanos u64><exchange nanos since midnight i64, -1 for none>
+// * <line length u32><line><file length u16><file>, little endian
+pub struct Spool {
+    path: PathBuf,
+    file: Option<File>,
+    read_offset: u64,
+    write_offset: u64,
+    // * Batches and lines written and not read back yet
+    pub batches: usize,
+    pub lines: usize,
+}
+
+// * One batch read back from the spool, removed with Spool::pop once delivered
+pub struct SpooledBatch {
+    pub writer: usize,
+    pub batch: Batch,
+    len: u64,
+    lines: usize,
+}
+
+impl Spool {
+    pub fn new(path: PathBuf) -> Spool {
+        Spool {
+            path,
+            file: None,
+            read_offset: 0,
+            write_offset: 0,
+            batches: 0,
+            lines: 0,
+        }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.write_offset - self.read_offset
+    }
+
+    async fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.path)
+                .await?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    pub async fn push(&mut self, writer: usize, batch: &[Entry]) -> io::Result<()> {
+        let mut payload = Vec::new();
+        for entry in batch {
+            let file = entry.file.as_deref().unwrap_or("").as_bytes();
+            payload.extend_from_slice(&entry.seq.to_le_bytes());
+            let stamp = &entry.stamp;
+            let exchange = stamp.exchange.map_or(-1, |time| {
+                time.num_seconds_from_midnight() as i64 * 1_000_000_000 + time.nanosecond() as i64
+            });
+            let received = stamp.received.timestamp_nanos_opt().unwrap_or_default();
+            payload.extend_from_slice(&received.to_le_bytes());
+            payload.extend_from_slice(&(stamp.monotonic.as_nanos() as u64).to_le_bytes());
+            payload.extend_from_slice(&exchange.to_le_bytes());
+            payload.extend_from_slice(&(entry.line.len() as u32).to_le_bytes());
+            payload.extend_from_slice(&entry.line);
+            payload.extend_from_slice(&(file.len() as u16).to_le_bytes());
+            payload.extend_from_slice(file);
+        }
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+        record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        record.extend_from_slice(&(writer as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let offset = self.write_offset;
+        let file = self.file().await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(&record).await?;
+        file.flush().await?;
+        self.write_offset += record.len() as u64;
+        self.batches += 1;
+        self.lines += batch.len();
+        Ok(())
+    }
+
+    // * The oldest batch, left in the spool until pop
+    pub async fn peek(&mut self) -> io::Result<Option<SpooledBatch>> {
+        if self.batches == 0 {
+            return Ok(None);
+        }
+        let offset = self.read_offset;
+        let file = self.file().await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut header).await?;
+        let len = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let writer = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact(&mut payload).await?;
+
+        let batch = decode(&payload)?;
+        Ok(Some(SpooledBatch {
+            writer,
+            lines: batch.len(),
+            batch,
+            len: HEADER_LEN + len,
+        }))
+    }
+
+    pub async fn pop(&mut self, spooled: &SpooledBatch) {
+        self.read_offset += spooled.len;
+        self.batches -= 1;
+        self.lines -= spooled.lines;
+        if self.batches == 0 {
+            self.clear().await;
+        } else if self.read_offset >= COMPACT_BYTES && self.read_offset >= self.bytes() {
+            if let Err(e) = self.compact().await {
+                error!(file = %self.path.display(), error = ?e, "Compact spool");
+            }
+        }
+    }
+
+    // * Moves the unread batches to the start of the file and truncates it. Only done when the read
+    // * part is at least as large, so a failed copy never overwrites batches still to be read
+    async fn compact(&mut self) -> io::Result<()> {
+        let (mut from, end) = (self.read_offset, self.write_offset);
+        let file = self.file().await?;
+        let mut to = 0;
+        let mut chunk = vec![0u8; COPY_CHUNK];
+        while from < end {
+            let len = COPY_CHUNK.min((end - from) as usize);
+            file.seek(SeekFrom::Start(from)).await?;
+            file.read_exact(&mut chunk[..len]).await?;
+            file.seek(SeekFrom::Start(to)).await?;
+            file.write_all(&chunk[..len]).await?;
+            from += len as u64;
+            to += len as u64;
+        }
+        file.flush().await?;
+        file.set_len(to).await?;
+        self.read_offset = 0;
+        self.write_offset = to;
+        Ok(())
+    }
+
+    // * Starts over with an empty file, returns the lines that were still in it
+    pub async fn clear(&mut self) -> usize {
+        let lines = self.lines;
+        self.read_offset = 0;
+        self.write_offset = 0;
+        self.batches = 0;
+        self.lines = 0;
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.set_len(0).await {
+                error!(file = %self.path.display(), error = ?e, "Truncate spool");
+            }
+        }
+        lines
+    }
+
+    pub async fn remove(&mut self) {
+        self.file = None;
+        if let Err(e) = remove_file(&self.path).await {
+            if e.kind() != ErrorKind::NotFound {
+                error!(file = %self.path.display(), error = ?e, "Remove spool");
+            }
+        }
+    }
+}
+
+fn decode(mut payload: &[u8]) -> io::Result<Batch> {
+    let corrupt = || io::Error::new(ErrorKind::InvalidData, "corrupt spool record");
+    let mut batch = Vec::new();
+    while !payload.is_empty() {
+        let (seq, rest) = take(payload, 8).ok_or_else(corrupt)?;
+        let (received, rest) = take(rest, 8).ok_or_else(corrupt)?;
+        let (monotonic, rest) = take(rest, 8).ok_or_else(corrupt)?;
+        let (exchange, rest) = take(rest, 8).ok_or_else(corrupt)?;
+        let (line_len, rest) = take(rest, 4).ok_or_else(corrupt)?;
+        let line_len = u32::from_le_bytes(line_len.try_into().unwrap()) as usize;
+        let (line, rest) = take(rest, line_len).ok_or_else(corrupt)?;
+        let (file_len, rest) = take(rest, 2).ok_or_else(corrupt)?;
+        let file_len = u16::from_le_bytes(file_len.try_into().unwrap()) as usize;
+        let (file, rest) = take(rest, file_len).ok_or_else(corrupt)?;
+        payload = rest;
+
+        let received =
+            DateTime::from_timestamp_nanos(i64::from_le_bytes(received.try_into().unwrap()));
+        let monotonic = Duration::from_nanos(u64::from_le_bytes(monotonic.try_into().unwrap()));
+        let exchange = i64::from_le_bytes(exchange.try_into().unwrap());
+        let exchange = (exchange >= 0)
+            .then(|| {
+                NaiveTime::from_num_seconds_from_midnight_opt(
+                    (exchange / 1_000_000_000) as u32,
+                    (exchange % 1_000_000_000) as u32,
+                )
+            })
+            .flatten();
+        let file = std::str::from_utf8(file).map_err(|_| corrupt())?;
+        batch.push(Entry {
+            seq: u64::from_le_bytes(seq.try_into().unwrap()),
+            stamp: Stamp {
+                received,
+                monotonic,
+                exchange,
+            },
+            line: line.to_vec(),
+            file: (!file.is_empty()).then(|| Arc::from(file)),
+        });
+    }
+    Ok(batch)
+}
+
+fn take(data: &[u8], len: usize) -> Option<(&[u8], &[u8])> {
+    (data.len() >= len).then(|| data.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+
+    fn entry(seq: u64, line: &str, file: Option<&str>) -> Entry {
+        Entry {
+            seq,
+            stamp: Stamp {
+                received: DateTime::from_timestamp(1714656615, 123456789 + seq as u32).unwrap(),
+                monotonic: Duration::new(12, 345),
+                exchange: seq
+                    .is_multiple_of(2)
+                    .then(|| NaiveTime::from_hms_milli_opt(10, 30, 15, 250).unwrap()),
+            },
+            line: line.as_bytes().to_vec(),
+            file: file.map(Arc::from),
+        }
+    }
+
+    fn assert_same(read: &[Entry], written: &[Entry]) {
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(written) {
+            assert_eq!(read.seq, written.seq);
+            assert_eq!(read.stamp, written.stamp);
+            assert_eq!(read.line, written.line);
+            assert_eq!(read.file, written.file);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_batches_in_order() {
+        let dir = TempDir::new("spool-round-trip");
+        let mut spool = Spool::new(dir.join("spool.bin"));
+        let first = vec![
+            entry(1, "T:PETR4:103015:2:38.50!\r\n", None),
+            entry(
+                2,
+                "B:PETR4:E!\r\n",
+                Some("content/crystal-md-symbol-petr4.txt"),
+            ),
+        ];
+        let second = vec![entry(3, "", None)];
+        spool.push(4, &first).await.unwrap();
+        spool.push(7, &second).await.unwrap();
+        assert_eq!((spool.batches, spool.lines), (2, 3));
+
+        let spooled = spool.peek().await.unwrap().unwrap();
+        assert_eq!(spooled.writer, 4);
+        assert_same(&spooled.batch, &first);
+        // * Peek leaves the batch in place until it is popped
+        assert_same(&spool.peek().await.unwrap().unwrap().batch, &first);
+        spool.pop(&spooled).await;
+
+        let spooled = spool.peek().await.unwrap().unwrap();
+        assert_eq!(spooled.writer, 7);
+        assert_same(&spooled.batch, &second);
+        spool.pop(&spooled).await;
+
+        assert!(spool.peek().await.unwrap().is_none());
+        assert_eq!((spool.batches, spool.lines, spool.bytes()), (0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn compacts_the_unread_batches() {
+        let dir = TempDir::new("spool-compact");
+        let mut spool = Spool::new(dir.join("spool.bin"));
+        let batches: Vec<Vec<Entry>> = (0..3)
+            .map(|seq| vec![entry(seq, "T:PETR4:103015:2:38.50!\r\n", None)])
+            .collect();
+        for batch in &batches {
+            spool.push(0, batch).await.unwrap();
+        }
+        for _ in 0..2 {
+            let spooled = spool.peek().await.unwrap().unwrap();
+            spool.pop(&spooled).await;
+        }
+        let bytes = spool.bytes();
+
+        spool.compact().await.unwrap();
+        assert_eq!(spool.read_offset, 0);
+        assert_eq!(spool.bytes(), bytes);
+        assert_eq!(tokio::fs::metadata(&spool.path).await.unwrap().len(), bytes);
+        assert_same(&spool.peek().await.unwrap().unwrap().batch, &batches[2]);
+
+        // * Batches pushed after the compaction follow the ones moved
+        spool.push(0, &batches[0]).await.unwrap();
+        let spooled = spool.peek().await.unwrap().unwrap();
+        spool.pop(&spooled).await;
+        assert_same(&spool.peek().await.unwrap().unwrap().batch, &batches[0]);
+    }
+
+    #[test]
+    fn rejects_corrupt_records() {
+        let error = decode(&[1, 2, 3]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,180 @@
+use chrono::{NaiveDate, NaiveTime};
+
+use super::parse::{
+    invalid, message_body, next_field, parse_date, parse_price, parse_quantity, parse_time,
+    ParseError,
+};
+
+// * Format: T:<symbol>:<HHMMSS>:<index>:<value>:<index>:<value>...!
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuoteUpdate {
+    pub symbol: String,
+    pub time: NaiveTime,
+    pub fields: Vec<QuoteField>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuoteField {
+    LastModifiedTime(NaiveTime),
+    LastModifiedDate(NaiveDate),
+    LastPrice(f64),
+    Bid(f64),
+    Ask(f64),
+    LastTradeTime(NaiveTime),
+    LastTradeQuantity(u64),
+    TradedQuantity(u64),
+    TradeCount(u64),
+    Volume(u64),
+    FinancialVolume(f64),
+    High(f64),
+    Low(f64),
+    PreviousClose(f64),
+    Open(f64),
+    BidTime(NaiveTime),
+    AskTime(NaiveTime),
+    BidVolume(u64),
+    AskVolume(u64),
+    // * Indexes we do not decode yet and values that did not match their type
+    Raw { index: u16, value: String },
+}
+
+impl QuoteField {
+    fn decode(index: u16, value: &str) -> QuoteField {
+        let typed = match index {
+            0 => parse_time("last modified time", value).map(QuoteField::LastModifiedTime),
+            1 => parse_date("last modified date", value).map(QuoteField::LastModifiedDate),
+            2 => parse_price("last price", value).map(QuoteField::LastPrice),
+            3 => parse_price("bid", value).map(QuoteField::Bid),
+            4 => parse_price("ask", value).map(QuoteField::Ask),
+            5 => parse_time("last trade time", value).map(QuoteField::LastTradeTime),
+            6 => parse_quantity("last trade quantity", value).map(QuoteField::LastTradeQuantity),
+            7 => parse_quantity("traded quantity", value).map(QuoteField::TradedQuantity),
+            8 => parse_quantity("trade count", value).map(QuoteField::TradeCount),
+            9 => parse_quantity("volume", value).map(QuoteField::Volume),
+            10 => parse_price("financial volume", value).map(QuoteField::FinancialVolume),
+            11 => parse_price("high", value).map(QuoteField::High),
+            12 => parse_price("low", value).map(QuoteField::Low),
+            13 => parse_price("previous close", value).map(QuoteField::PreviousClose),
+            14 => parse_price("open", value).map(QuoteField::Open),
+            15 => parse_time("bid time", value).map(QuoteField::BidTime),
+            16 => parse_time("ask time", value).map(QuoteField::AskTime),
+            19 => parse_quantity("bid volume", value).map(QuoteField::BidVolume),
+            20 => parse_quantity("ask volume", value).map(QuoteField::AskVolume),
+            _ => return QuoteField::raw(index, value),
+        };
+        typed.unwrap_or_else(|_| QuoteField::raw(index, value))
+    }
+
+    fn raw(index: u16, value: &str) -> QuoteField {
+        QuoteField::Raw {
+            index,
+            value: value.to_string(),
+        }
+    }
+}
+
+// * Returns None when the line is not an SQT quote message
+pub fn parse(line: &[u8]) -> Option<Result<QuoteUpdate, ParseError>> {
+    message_body(line, "T").map(|body| body.and_then(parse_body))
+}
+
+fn parse_body(body: &str) -> Result<QuoteUpdate, ParseError> {
+    let mut fields = body.split(':');
+    let symbol = next_field(&mut fields, "symbol")?;
+    if symbol.is_empty() {
+        return Err(ParseError::Truncated("symbol"));
+    }
+    let time = parse_time("time", next_field(&mut fields, "time")?)?;
+
+    let mut update = QuoteUpdate {
+        symbol: symbol.to_string(),
+        time,
+        fields: Vec::new(),
+    };
+
+    while let Some(index) = fields.next() {
+        if index.is_empty() {
+            continue;
+        }
+        let index = index
+            .parse::<u16>()
+            .map_err(|_| invalid("field index", index))?;
+        let value = next_field(&mut fields, "field value")?;
+        update.fields.push(QuoteField::decode(index, value));
+    }
+
+    Ok(update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(h: u32, m: u32, s: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, s).unwrap()
+    }
+
+    #[test]
+    fn parses_quote_fields() {
+        let line = b"T:PETR4:103015:0:103014:1:20240502:2:38.50:3:38.49:4:38.51:7:1200:9:1500!\r\n";
+        let update = parse(line).unwrap().unwrap();
+        assert_eq!(update.symbol, "PETR4");
+        assert_eq!(update.time, time(10, 30, 15));
+        assert_eq!(
+            update.fields,
+            vec![
+                QuoteField::LastModifiedTime(time(10, 30, 14)),
+                QuoteField::LastModifiedDate(NaiveDate::from_ymd_opt(2024, 5, 2).unwrap()),
+                QuoteField::LastPrice(38.5),
+                QuoteField::Bid(38.49),
+                QuoteField::Ask(38.51),
+                QuoteField::TradedQuantity(1200),
+                QuoteField::Volume(1500),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_and_mistyped_fields_raw() {
+        let update = parse(b"T:VALE3:101500:42:abc:2:n/a!").unwrap().unwrap();
+        assert_eq!(
+            update.fields,
+            vec![
+                QuoteField::Raw {
+                    index: 42,
+                    value: "abc".to_string()
+                },
+                QuoteField::Raw {
+                    index: 2,
+                    value: "n/a".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        assert!(parse(b"B:PETR4:A:1:0:C:38.50:100:1:103015!").is_none());
+        assert!(parse(b"You are connected").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_quotes() {
+        assert_eq!(
+            parse(b"T::103015!").unwrap(),
+            Err(ParseError::Truncated("symbol"))
+        );
+        assert_eq!(
+            parse(b"T:PETR4:1030!").unwrap(),
+            Err(invalid("time", "1030"))
+        );
+        assert_eq!(
+            parse(b"T:PETR4:103015:2!").unwrap(),
+            Err(ParseError::Truncated("field value"))
+        );
+        assert_eq!(
+            parse(b"T:PETR4:103015:x:1!").unwrap(),
+            Err(invalid("field index", "x"))
+        );
+    }
+}
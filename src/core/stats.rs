@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    // * cb - lines handed to writers and not yet flushed
+    pub lines_queued: AtomicUsize,
+    // * cr - lines handed to writers since the session started
+    pub lines_sent: AtomicUsize,
+    pub malformed_quotes: AtomicUsize,
+    pub malformed_trades: AtomicUsize,
+    pub reconnects: AtomicUsize,
+    // * mb - bytes of lines and events held in memory, bounded by CHITA_BUFFER_BYTES
+    pub bytes_buffered: AtomicUsize,
+    // * sl - lines written to the spool file since the session started
+    pub lines_spilled: AtomicUsize,
+    // * sb - bytes in the spool file waiting to be written back
+    pub spool_bytes: AtomicUsize,
+    // * dl - lines lost because no writer or spool could take them
+    pub lines_dropped: AtomicUsize,
+    // * de - events left out of the Parquet files because the budget was full
+    pub events_dropped: AtomicUsize,
+    // * ue - Crystal errors without a symbol, not matched to any command
+    pub unattributed_errors: AtomicUsize,
+}
+
+impl CaptureStats {
+    pub fn report(&self) -> String {
+        format!(
+            "cb: {}, cr: {}, mq: {}, mt: {}, rc: {}, mb: {}, sl: {}, sb: {}, dl: {}, de: {}, ue: {}",
+            self.lines_queued.load(Ordering::SeqCst),
+            self.lines_sent.load(Ordering::SeqCst),
+            self.malformed_quotes.load(Ordering::SeqCst),
+            self.malformed_trades.load(Ordering::SeqCst),
+            self.reconnects.load(Ordering::SeqCst),
+            self.bytes_buffered.load(Ordering::SeqCst),
+            self.lines_spilled.load(Ordering::SeqCst),
+            self.spool_bytes.load(Ordering::SeqCst),
+            self.lines_dropped.load(Ordering::SeqCst),
+            self.events_dropped.load(Ordering::SeqCst),
+            self.unattributed_errors.load(Ordering::SeqCst)
+        )
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use super::parse::{message_body, ParseError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Feed {
+    Book,
+    Trades,
+    Quotes,
+}
+
+pub const ALL_FEEDS: [Feed; 3] = [Feed::Book, Feed::Trades, Feed::Quotes];
+
+impl Feed {
+    pub fn subscribe_command(&self, symbol: &str) -> String {
+        match self {
+            Feed::Book => format!("BQT {}\n", symbol),
+            Feed::Trades => format!("GQT {} S 1\n", symbol),
+            Feed::Quotes => format!("SQT {}\n", symbol),
+        }
+    }
+
+    pub fn unsubscribe_command(&self, symbol: &str) -> String {
+        match self {
+            Feed::Book => format!("UBQ {}\n", symbol),
+            Feed::Trades => format!("UGQ {}\n", symbol),
+            Feed::Quotes => format!("USQ {}\n", symbol),
+        }
+    }
+
+    // * B - book, V - trades, T - quotes
+    fn from_tag(tag: u8) -> Option<Feed> {
+        match tag {
+            b'B' => Some(Feed::Book),
+            b'V' => Some(Feed::Trades),
+            b'T' => Some(Feed::Quotes),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Feed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Feed::Book => write!(f, "book"),
+            Feed::Trades => write!(f, "trades"),
+            Feed::Quotes => write!(f, "quotes"),
+        }
+    }
+}
+
+impl FromStr for Feed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "book" | "bqt" => Ok(Feed::Book),
+            "trades" | "gqt" => Ok(Feed::Trades),
+            "quotes" | "sqt" => Ok(Feed::Quotes),
+            other => Err(format!("Error: unknown feed {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionStatus {
+    Pending,
+    Active,
+    Rejected(String),
+}
+
+impl fmt::Display for SubscriptionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionStatus::Pending => write!(f, "pending"),
+            SubscriptionStatus::Active => write!(f, "active"),
+            SubscriptionStatus::Rejected(reason) => write!(f, "rejected ({})", reason),
+        }
+    }
+}
+
+// * Format: book,trades,quotes
+pub fn parse_feeds(feeds: &str) -> Result<Vec<Feed>, String> {
+    feeds.split(',').map(str::parse).collect()
+}
+
+// * One line of the subscriptions file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionRequest {
+    pub symbol: String,
+    pub feeds: Vec<Feed>,
+    pub subscribe: bool,
+}
+
+impl SubscriptionRequest {
+    // * Format: [-]<symbol> [book,trades,quotes], - unsubscribes, all feeds by default.
+    // * Returns None for blank lines and # comments
+    pub fn parse(line: &str) -> Option<Result<SubscriptionRequest, String>> {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            return None;
+        }
+        let mut fields = line.split_whitespace();
+        let symbol = fields.next().unwrap_or_default();
+        let (symbol, subscribe) = match symbol.strip_prefix('-') {
+            Some(symbol) => (symbol, false),
+            None => (symbol, true),
+        };
+        let feeds = match fields.next() {
+            Some(feeds) => parse_feeds(feeds),
+            None => Ok(ALL_FEEDS.to_vec()),
+        };
+        Some(feeds.map(|feeds| SubscriptionRequest {
+            symbol: normalize(symbol),
+            feeds,
+            subscribe,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionCommand {
+    pub symbol: String,
+    pub feed: Feed,
+    pub subscribe: bool,
+}
+
+impl SubscriptionCommand {
+    pub fn command(&self) -> String {
+        if self.subscribe {
+            self.feed.subscribe_command(&self.symbol)
+        } else {
+            self.feed.unsubscribe_command(&self.symbol)
+        }
+    }
+}
+
+// * Format: E:<code>:<message>! or E:<symbol>:<code>:<message>!
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrystalError {
+    pub symbol: Option<String>,
+    pub code: String,
+    pub message: String,
+}
+
+impl CrystalError {
+    // * Returns None when the line is not a Crystal error message
+    pub fn parse(line: &[u8]) -> Option<Result<CrystalError, ParseError>> {
+        message_body(line, "E").map(|body| {
+            body.map(|body| {
+                let mut fields = body.splitn(3, ':');
+                let first = fields.next().unwrap_or_default();
+                if first.chars().all(|c| c.is_ascii_digit()) {
+                    let rest: Vec<&str> = fields.collect();
+                    CrystalError {
+                        symbol: None,
+                        code: first.to_string(),
+                        message: rest.join(":"),
+                    }
+                } else {
+                    CrystalError {
+                        symbol: Some(normalize(first)),
+                        code: fields.next().unwrap_or_default().to_string(),
+                        message: fields.next().unwrap_or_default().to_string(),
+                    }
+                }
+            })
+        })
+    }
+
+    pub fn reason(&self) -> String {
+        if self.message.is_empty() {
+            format!("E:{}", self.code)
+        } else {
+            format!("E:{} {}", self.code, self.message)
+        }
+    }
+}
+
+pub type SubscriptionList = Vec<(String, Vec<(Feed, SubscriptionStatus)>)>;
+
+// * The authoritative set of subscriptions, replayed on every new connection
+#[derive(Debug, Default, Clone)]
+pub struct Subscriptions {
+    symbols: BTreeMap<String, BTreeMap<Feed, SubscriptionStatus>>,
+    // * Commands sent on the current connection, in the order Crystal answers them
+    in_flight: VecDeque<(String, Feed)>,
+    pending: usize,
+}
+
+impl Subscriptions {
+    pub fn new(symbols: impl IntoIterator<Item = String>) -> Subscriptions {
+        let mut subscriptions = Subscriptions::default();
+        for symbol in symbols {
+            for feed in ALL_FEEDS {
+                subscriptions.insert(&symbol, feed);
+            }
+        }
+        subscriptions
+    }
+
+    // * Returns false when the symbol is already subscribed to the feed,
+    // * a rejected subscription is retried
+    pub fn insert(&mut self, symbol: &str, feed: Feed) -> bool {
+        let feeds = self.symbols.entry(normalize(symbol)).or_default();
+        match feeds.get(&feed) {
+            Some(SubscriptionStatus::Pending) | Some(SubscriptionStatus::Active) => false,
+            _ => {
+                feeds.insert(feed, SubscriptionStatus::Pending);
+                self.pending += 1;
+                true
+            }
+        }
+    }
+
+    // * Returns false when the symbol was not subscribed to the feed
+    pub fn remove(&mut self, symbol: &str, feed: Feed) -> bool {
+        let symbol = normalize(symbol);
+        let Some(feeds) = self.symbols.get_mut(&symbol) else {
+            return false;
+        };
+        let removed = feeds.remove(&feed);
+        if removed == Some(SubscriptionStatus::Pending) {
+            self.pending -= 1;
+        }
+        if feeds.is_empty() {
+            self.symbols.remove(&symbol);
+        }
+        removed.is_some()
+    }
+
+    pub fn list(&self) -> SubscriptionList {
+        self.symbols
+            .iter()
+            .map(|(symbol, feeds)| {
+                let feeds = feeds
+                    .iter()
+                    .map(|(feed, status)| (*feed, status.clone()))
+                    .collect();
+                (symbol.clone(), feeds)
+            })
+            .collect()
+    }
+
+    // * Everything not rejected goes back to pending for a new connection
+    pub fn reset_for_connection(&mut self) -> Vec<(String, Vec<Feed>)> {
+        self.in_flight.clear();
+        self.pending = 0;
+        let mut to_subscribe = Vec::with_capacity(self.symbols.len());
+        for (symbol, feeds) in self.symbols.iter_mut() {
+            let mut symbol_feeds = Vec::new();
+            for (feed, status) in feeds.iter_mut() {
+                if let SubscriptionStatus::Rejected(_) = status {
+                    continue;
+                }
+                *status = SubscriptionStatus::Pending;
+                self.pending += 1;
+                symbol_feeds.push(*feed);
+            }
+            if !symbol_feeds.is_empty() {
+                to_subscribe.push((symbol.clone(), symbol_feeds));
+            }
+        }
+        to_subscribe
+    }
+
+    pub fn sent(&mut self, symbol: &str, feed: Feed) {
+        self.in_flight.push_back((normalize(symbol), feed));
+    }
+
+    // * The first data message for a pending subscription confirms it
+    pub fn acknowledge(&mut self, line: &[u8]) {
+        if self.pending == 0 {
+            return;
+        }
+        if line.get(1) != Some(&b':') {
+            return;
+        }
+        let Some(feed) = line.first().and_then(|&tag| Feed::from_tag(tag)) else {
+            return;
+        };
+        let Some(symbol) = line
+            .get(2..)
+            .and_then(|rest| rest.split(|&b| b == b':').next())
+            .and_then(|symbol| std::str::from_utf8(symbol).ok())
+        else {
+            return;
+        };
+
+        let status = self
+            .symbols
+            .get_mut(&normalize(symbol))
+            .and_then(|feeds| feeds.get_mut(&feed));
+        if let Some(status @ SubscriptionStatus::Pending) = status {
+            *status = SubscriptionStatus::Active;
+            self.pending -= 1;
+        }
+    }
+
+    // * Errors without a symbol cannot be told apart, so they are not blamed on any command
+    pub fn reject(&mut self, error: &CrystalError) -> Option<(String, Feed)> {
+        let symbol = error.symbol.as_ref()?;
+        let position = self.in_flight.iter().position(|(in_flight, feed)| {
+            in_flight == symbol
+                && self.status(in_flight, *feed) == Some(&SubscriptionStatus::Pending)
+        })?;
+        let target = self.in_flight.remove(position)?;
+
+        let status = self
+            .symbols
+            .get_mut(&target.0)
+            .and_then(|feeds| feeds.get_mut(&target.1))?;
+        *status = SubscriptionStatus::Rejected(error.reason());
+        self.pending -= 1;
+        Some(target)
+    }
+
+    pub fn rejected(&self) -> Vec<(String, Feed, String)> {
+        self.symbols
+            .iter()
+            .flat_map(|(symbol, feeds)| {
+                feeds.iter().filter_map(move |(feed, status)| match status {
+                    SubscriptionStatus::Rejected(reason) => {
+                        Some((symbol.clone(), *feed, reason.clone()))
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+
+    fn status(&self, symbol: &str, feed: Feed) -> Option<&SubscriptionStatus> {
+        self.symbols.get(symbol).and_then(|feeds| feeds.get(&feed))
+    }
+}
+
+// * FNV-1a keeps the assignment stable across runs and restarts
+pub fn shard_of(symbol: &str, shards: usize) -> usize {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in normalize(symbol).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash % shards.max(1) as u64) as usize
+}
+
+// * Crystal commands are sent with lowercase symbols
+pub fn normalize(symbol: &str) -> String {
+    symbol.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // * PETR4 and VALE3 on every feed, all commands sent on a new connection
+    fn connected() -> Subscriptions {
+        let mut subscriptions = Subscriptions::new(["PETR4".to_string(), "VALE3".to_string()]);
+        for (symbol, feeds) in subscriptions.reset_for_connection() {
+            for feed in feeds {
+                subscriptions.sent(&symbol, feed);
+            }
+        }
+        subscriptions
+    }
+
+    fn error(line: &[u8]) -> CrystalError {
+        CrystalError::parse(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parses_errors_with_and_without_a_symbol() {
+        assert_eq!(
+            error(b"E:PETR4:2:Symbol not found!\r\n"),
+            CrystalError {
+                symbol: Some("petr4".to_string()),
+                code: "2".to_string(),
+                message: "Symbol not found".to_string(),
+            }
+        );
+        let unnamed = error(b"E:1:Not authorized:quotes!");
+        assert_eq!(unnamed.symbol, None);
+        assert_eq!(unnamed.reason(), "E:1 Not authorized:quotes");
+        assert!(CrystalError::parse(b"T:PETR4:103015!").is_none());
+    }
+
+    #[test]
+    fn data_lines_acknowledge_pending_subscriptions() {
+        let mut subscriptions = connected();
+        subscriptions.acknowledge(b"T:PETR4:103015:2:38.50!\r\n");
+        assert_eq!(
+            subscriptions.status("petr4", Feed::Quotes),
+            Some(&SubscriptionStatus::Active)
+        );
+        assert_eq!(
+            subscriptions.status("petr4", Feed::Book),
+            Some(&SubscriptionStatus::Pending)
+        );
+        assert_eq!(subscriptions.pending, 5);
+    }
+
+    #[test]
+    fn rejected_symbols_are_left_out_of_the_next_connection() {
+        let mut subscriptions = connected();
+        assert_eq!(
+            subscriptions.reject(&error(b"E:VALE3:2:Symbol not found!")),
+            Some(("vale3".to_string(), Feed::Book))
+        );
+        assert_eq!(
+            subscriptions.rejected(),
+            vec![(
+                "vale3".to_string(),
+                Feed::Book,
+                "E:2 Symbol not found".to_string()
+            )]
+        );
+
+        let to_subscribe = subscriptions.reset_for_connection();
+        assert_eq!(
+            to_subscribe,
+            vec![
+                ("petr4".to_string(), ALL_FEEDS.to_vec()),
+                ("vale3".to_string(), vec![Feed::Trades, Feed::Quotes]),
+            ]
+        );
+        // * Subscribing again retries the rejected feed
+        assert!(subscriptions.insert("VALE3", Feed::Book));
+        assert!(!subscriptions.insert("VALE3", Feed::Trades));
+    }
+
+    #[test]
+    fn errors_without_a_symbol_are_not_blamed_on_a_command() {
+        let mut subscriptions = connected();
+        subscriptions.acknowledge(b"B:PETR4:E!");
+        assert_eq!(subscriptions.reject(&error(b"E:1:Not authorized!")), None);
+        assert!(subscriptions.rejected().is_empty());
+        assert_eq!(subscriptions.pending, 5);
+    }
+
+    #[test]
+    fn shards_by_the_normalized_symbol() {
+        assert_eq!(shard_of("PETR4", 4), shard_of(" petr4 ", 4));
+        assert_eq!(shard_of("PETR4", 1), 0);
+        assert_eq!(shard_of("PETR4", 0), 0);
+
+        let mut counts = [0; 4];
+        for n in 0..400 {
+            counts[shard_of(&format!("SYM{}", n), 4)] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 50), "{:?}", counts);
+    }
+
+    #[test]
+    fn parses_subscription_requests() {
+        assert_eq!(
+            SubscriptionRequest::parse(" PETR4  # all feeds"),
+            Some(Ok(SubscriptionRequest {
+                symbol: "petr4".to_string(),
+                feeds: ALL_FEEDS.to_vec(),
+                subscribe: true,
+            }))
+        );
+        assert_eq!(
+            SubscriptionRequest::parse("-VALE3 book,SQT"),
+            Some(Ok(SubscriptionRequest {
+                symbol: "vale3".to_string(),
+                feeds: vec![Feed::Book, Feed::Quotes],
+                subscribe: false,
+            }))
+        );
+        assert_eq!(SubscriptionRequest::parse("  # comment"), None);
+        assert!(SubscriptionRequest::parse("PETR4 news").unwrap().is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::helpers::config::{
+    proxy, tls_ca_file, tls_client_cert, tls_client_key, tls_server_name, transport,
+};
+
+const MAX_PROXY_RESPONSE: usize = 8192;
+
+pub trait CrystalStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> CrystalStream for T {}
+
+pub type BoxedStream = Box<dyn CrystalStream>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Proxy {
+    Socks5 {
+        address: String,
+        credentials: Option<(String, String)>,
+    },
+    HttpConnect {
+        address: String,
+        credentials: Option<(String, String)>,
+    },
+}
+
+// * How the Crystal connection is opened, chosen by CHITA_TRANSPORT and CHITA_PROXY
+#[derive(Clone)]
+pub struct Transport {
+    tls: Option<TlsConnector>,
+    server_name: Option<String>,
+    proxy: Option<Proxy>,
+}
+
+impl Transport {
+    pub fn from_config() -> Result<Transport, Box<dyn Error>> {
+        let tls = match transport().to_lowercase().as_str() {
+            "" | "plain" => None,
+            "tls" => Some(tls_connector()?),
+            other => return Err(format!("Error: unknown CHITA_TRANSPORT {}", other).into()),
+        };
+        let server_name = tls_server_name();
+
+        Ok(Transport {
+            tls,
+            server_name: if server_name.is_empty() {
+                None
+            } else {
+                Some(server_name)
+            },
+            proxy: parse_proxy(&proxy())?,
+        })
+    }
+
+    pub async fn connect(&self, address: &str) -> io::Result<BoxedStream> {
+        let stream = match &self.proxy {
+            None => TcpStream::connect(address).await?,
+            Some(Proxy::Socks5 {
+                address: proxy,
+                credentials,
+            }) => {
+                let stream = match credentials {
+                    Some((username, password)) => {
+                        Socks5Stream::connect_with_password(
+                            proxy.as_str(),
+                            address,
+                            username,
+                            password,
+                        )
+                        .await
+                    }
+                    None => Socks5Stream::connect(proxy.as_str(), address).await,
+                };
+                stream
+                    .map_err(|e| io::Error::other(format!("SOCKS5 proxy {} - {}", proxy, e)))?
+                    .into_inner()
+            }
+            Some(Proxy::HttpConnect {
+                address: proxy,
+                credentials,
+            }) => http_connect(proxy, address, credentials.as_ref()).await?,
+        };
+
+        let Some(connector) = &self.tls else {
+            return Ok(Box::new(stream));
+        };
+        let host = match &self.server_name {
+            Some(name) => name.clone(),
+            None => host_of(address).to_string(),
+        };
+        let server_name = ServerName::try_from(host)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Box::new(connector.connect(server_name, stream).await?))
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.tls.is_some() { "tls" } else { "plain" })?;
+        match &self.proxy {
+            Some(Proxy::Socks5 { address, .. }) => write!(f, " via socks5 {}", address),
+            Some(Proxy::HttpConnect { address, .. }) => write!(f, " via http {}", address),
+            None => Ok(()),
+        }
+    }
+}
+
+fn tls_connector() -> Result<TlsConnector, Box<dyn Error>> {
+    let mut roots = RootCertStore::empty();
+    let ca_file = tls_ca_file();
+    if ca_file.is_empty() {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    } else {
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(&ca_file)?)) {
+            roots.add(cert?)?;
+        }
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+
+    let (cert_file, key_file) = (tls_client_cert(), tls_client_key());
+    let config = match (cert_file.is_empty(), key_file.is_empty()) {
+        (true, true) => builder.with_no_client_auth(),
+        (false, false) => {
+            let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&cert_file)?))
+                .collect::<Result<Vec<_>, _>>()?;
+            let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&key_file)?))?
+                .ok_or("Error: no private key in CHITA_TLS_CLIENT_KEY")?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        _ => {
+            return Err(
+                "Error: CHITA_TLS_CLIENT_CERT and CHITA_TLS_CLIENT_KEY must be set together".into(),
+            )
+        }
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn parse_proxy(url: &str) -> Result<Option<Proxy>, Box<dyn Error>> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok(None);
+    }
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| format!("Error: invalid CHITA_PROXY {}", url))?;
+    let rest = rest.trim_end_matches('/');
+    let (credentials, address) = match rest.rsplit_once('@') {
+        Some((userinfo, address)) => {
+            let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            (
+                Some((username.to_string(), password.to_string())),
+                address.to_string(),
+            )
+        }
+        None => (None, rest.to_string()),
+    };
+
+    match scheme.to_lowercase().as_str() {
+        "socks5" | "socks5h" => Ok(Some(Proxy::Socks5 {
+            address,
+            credentials,
+        })),
+        "http" => Ok(Some(Proxy::HttpConnect {
+            address,
+            credentials,
+        })),
+        other => Err(format!("Error: unsupported proxy scheme {}", other).into()),
+    }
+}
+
+// * Reads the response byte by byte so nothing after the headers is consumed
+async fn http_connect(
+    proxy: &str,
+    address: &str,
+    credentials: Option<&(String, String)>,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", address);
+    if let Some((username, password)) = credentials {
+        let token = STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::with_capacity(256);
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_PROXY_RESPONSE {
+            return Err(io::Error::other(format!(
+                "HTTP proxy {} - response headers too long",
+                proxy
+            )));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(stream),
+        _ => Err(io::Error::other(format!(
+            "HTTP proxy {} - {}",
+            proxy, status
+        ))),
+    }
+}
+
+// * host:port or [ipv6]:port
+fn host_of(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(username: &str, password: &str) -> Option<(String, String)> {
+        Some((username.to_string(), password.to_string()))
+    }
+
+    #[test]
+    fn parses_proxy_urls() {
+        assert_eq!(parse_proxy(" ").unwrap(), None);
+        assert_eq!(
+            parse_proxy("socks5://10.0.0.1:1080").unwrap(),
+            Some(Proxy::Socks5 {
+                address: "10.0.0.1:1080".to_string(),
+                credentials: None,
+            })
+        );
+        assert_eq!(
+            parse_proxy("SOCKS5H://user:p@ss@proxy:1080/").unwrap(),
+            Some(Proxy::Socks5 {
+                address: "proxy:1080".to_string(),
+                credentials: credentials("user", "p@ss"),
+            })
+        );
+        assert_eq!(
+            parse_proxy("http://user@proxy:3128").unwrap(),
+            Some(Proxy::HttpConnect {
+                address: "proxy:3128".to_string(),
+                credentials: credentials("user", ""),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_proxies() {
+        assert!(parse_proxy("proxy:3128").is_err());
+        assert!(parse_proxy("https://proxy:3128").is_err());
+    }
+
+    #[test]
+    fn takes_the_host_for_the_server_name() {
+        assert_eq!(host_of("crystal.example.com:9000"), "crystal.example.com");
+        assert_eq!(host_of("[::1]:9000"), "::1");
+        assert_eq!(host_of("crystal.example.com"), "crystal.example.com");
+    }
+}
@@ -0,0 +1,80 @@
+use chrono::{NaiveTime, Utc};
+use tokio::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::helpers::config::{
+    idle_timeout, idle_timeout_market, market_close, market_open, ping_command,
+};
+
+// * Tracks the last byte received on a Crystal connection
+pub struct IdleWatchdog {
+    last_byte: Instant,
+    pinged: bool,
+    ping: Option<String>,
+    idle_timeout: Duration,
+    idle_timeout_market: Duration,
+    market_hours: Option<(NaiveTime, NaiveTime)>,
+}
+
+impl IdleWatchdog {
+    pub fn new() -> IdleWatchdog {
+        let ping = ping_command();
+        let market_hours = match (
+            NaiveTime::parse_from_str(&market_open(), "%H:%M"),
+            NaiveTime::parse_from_str(&market_close(), "%H:%M"),
+        ) {
+            (Ok(open), Ok(close)) => Some((open, close)),
+            _ => {
+                warn!("Invalid CHITA_MARKET_OPEN or CHITA_MARKET_CLOSE");
+                None
+            }
+        };
+
+        IdleWatchdog {
+            last_byte: Instant::now(),
+            pinged: false,
+            ping: if ping.is_empty() {
+                None
+            } else {
+                Some(ping + "\n")
+            },
+            idle_timeout: Duration::from_secs(idle_timeout()),
+            idle_timeout_market: Duration::from_secs(idle_timeout_market()),
+            market_hours,
+        }
+    }
+
+    pub fn feed(&mut self) {
+        self.last_byte = Instant::now();
+        self.pinged = false;
+    }
+
+    pub fn threshold(&self) -> Duration {
+        let now = Utc::now().time();
+        match self.market_hours {
+            Some((open, close)) if open <= now && now < close => self.idle_timeout_market,
+            _ => self.idle_timeout,
+        }
+    }
+
+    pub fn idle_deadline(&self) -> Instant {
+        self.last_byte + self.threshold()
+    }
+
+    // * None once the ping was sent or when pings are disabled
+    pub fn ping_deadline(&self) -> Option<Instant> {
+        match (&self.ping, self.pinged) {
+            (Some(_), false) => Some(self.last_byte + self.threshold() / 2),
+            _ => None,
+        }
+    }
+
+    pub fn take_ping(&mut self) -> Option<String> {
+        self.pinged = true;
+        self.ping.clone()
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_byte.elapsed()
+    }
+}
@@ -0,0 +1,913 @@
+use chrono::{DateTime, Local, Utc};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{interval, sleep, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::helpers::config::{buffer_bytes, parquet_rows};
+use crate::helpers::errors::report_error;
+use crate::helpers::metrics::METRICS;
+
+use super::budget::{MemoryBudget, Reservation};
+use super::capture::{self, Stamp, TimestampFormat};
+use super::compression::Compression;
+use super::events::{self, EventMessage, EventRecord, MarketEvent, EVENT_SIZE};
+use super::segments;
+use super::spool::Spool;
+use super::stats::CaptureStats;
+use super::subscriptions::{normalize, shard_of};
+
+const MAX_BUFFER_SIZE: usize = 1000000;
+pub const BATCH_SIZE: usize = 10000;
+pub const NUM_WRITERS: usize = 20;
+const FLUSH_INTERVAL: u64 = 300;
+const RETRY_INTERVAL: u64 = 5;
+const MAX_RETRIES: usize = 10;
+const MAX_OPEN_FILES: usize = 128;
+// * Compressed files only get a frame per batch once this much is pending, the rest waits for the timer
+const MIN_FRAME_SIZE: usize = 256 * 1024;
+const CONTENT_DIR: &str = "content";
+const SPOOL_FILE: &str = "crystal-spool.bin";
+const DRAIN_INTERVAL: u64 = 1;
+// * Rough size of an entry besides its line
+const ENTRY_OVERHEAD: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    // * crystal-md-<writer>.txt, merged into one stream when the session ends
+    Batch,
+    // * crystal-md-symbol-<symbol>.txt
+    Symbol,
+    // * crystal-md-bucket-<k>.txt with k = hash(symbol) % n
+    Bucket(usize),
+}
+
+impl OutputMode {
+    // * Format: batch | symbol | bucket:<n>
+    pub fn parse(value: &str) -> Result<OutputMode, String> {
+        let value = value.trim().to_lowercase();
+        match value.as_str() {
+            "" | "batch" => Ok(OutputMode::Batch),
+            "symbol" => Ok(OutputMode::Symbol),
+            _ => match value.strip_prefix("bucket:").map(str::parse::<usize>) {
+                Some(Ok(buckets)) if buckets > 0 => Ok(OutputMode::Bucket(buckets)),
+                _ => Err(format!("Error: invalid CHITA_OUTPUT_MODE {}", value)),
+            },
+        }
+    }
+}
+
+pub struct Entry {
+    pub seq: u64,
+    pub stamp: Stamp,
+    pub line: Vec<u8>,
+    // * None keeps the line in the writer's own file
+    pub file: Option<Arc<str>>,
+}
+
+pub type Batch = Vec<Entry>;
+
+enum WriterMessage {
+    // * The reservation is given back once the batch is written
+    Batch(Batch, Reservation),
+    // * Closes every file written since the last rotation and moves it into the folder
+    Rotate(PathBuf, oneshot::Sender<()>),
+}
+
+// * Writers live for the whole session and finish once every handle is dropped
+#[derive(Clone)]
+pub struct WriterPool {
+    txs: Vec<mpsc::Sender<WriterMessage>>,
+    next: Arc<AtomicUsize>,
+    // * Shared by every connection so the order is kept across all the files
+    sequence: Arc<AtomicU64>,
+    mode: OutputMode,
+    compression: Compression,
+    // * Monotonic start of the session, Stamp::monotonic counts from here
+    started: Instant,
+    // * Normalized events for the Parquet writer, None when it is disabled
+    events: Option<mpsc::Sender<EventMessage>>,
+    // * Batches past the budget wait in the spool, and new ones queue behind them
+    budget: MemoryBudget,
+    spool: Arc<Mutex<Spool>>,
+    stats: Arc<CaptureStats>,
+}
+
+// * Each connection fills its own batches and hands them to the shared writers
+pub struct BatchWriter {
+    pool: WriterPool,
+    // * One batch per writer in the partitioned modes, only the first is used in batch mode
+    pending: Vec<Batch>,
+    len: usize,
+    // * Symbol to (writer, file), so the file name is built once per symbol
+    partitions: HashMap<String, (usize, Arc<str>)>,
+    // * Sequence number and arrival time of the last line pushed
+    last: (u64, DateTime<Utc>),
+}
+
+impl WriterPool {
+    pub fn start(
+        tracker: &TaskTracker,
+        stats: Arc<CaptureStats>,
+        mode: OutputMode,
+        compression: Compression,
+        format: TimestampFormat,
+    ) -> WriterPool {
+        let mut txs = Vec::with_capacity(NUM_WRITERS);
+        let spool = Arc::new(Mutex::new(Spool::new(
+            Path::new(CONTENT_DIR).join(SPOOL_FILE),
+        )));
+
+        for i in 0..NUM_WRITERS {
+            let (tx, rx) = mpsc::channel::<WriterMessage>(MAX_BUFFER_SIZE);
+            txs.push(tx);
+            tracker.spawn(
+                run_writer(
+                    i,
+                    rx,
+                    compression,
+                    format,
+                    Arc::clone(&spool),
+                    Arc::clone(&stats),
+                )
+                .instrument(info_span!("writer", writer = i)),
+            );
+        }
+
+        let rows = parquet_rows();
+        let events = (rows > 0).then(|| {
+            let (tx, rx) = mpsc::channel::<EventMessage>(events::MAX_EVENT_BUFFER);
+            let started = Local::now();
+            let span = info_span!("events");
+            tracker.spawn_blocking(move || {
+                span.in_scope(|| {
+                    events::run_event_writer(rx, PathBuf::from(CONTENT_DIR), rows, started)
+                })
+            });
+            tx
+        });
+
+        WriterPool {
+            txs,
+            next: Arc::new(AtomicUsize::new(0)),
+            sequence: Arc::new(AtomicU64::new(1)),
+            mode,
+            compression,
+            started: Instant::now(),
+            events,
+            budget: MemoryBudget::new(buffer_bytes(), Arc::clone(&stats)),
+            spool,
+            stats,
+        }
+    }
+
+    pub fn batcher(&self) -> BatchWriter {
+        BatchWriter {
+            pool: self.clone(),
+            pending: (0..NUM_WRITERS).map(|_| Vec::new()).collect(),
+            len: 0,
+            partitions: HashMap::new(),
+            last: (0, Utc::now()),
+        }
+    }
+
+    // * Returns once every file written so far is closed and inside the folder.
+    // * Lines still in the connection batches go to the next segment
+    pub async fn rotate(&self, dir: &Path) {
+        let mut acks = Vec::new();
+        for tx in &self.txs {
+            let (ack, done) = oneshot::channel();
+            if tx
+                .send(WriterMessage::Rotate(dir.to_path_buf(), ack))
+                .await
+                .is_ok()
+            {
+                acks.push(done);
+            }
+        }
+        if let Some(events) = &self.events {
+            let (ack, done) = oneshot::channel();
+            if events
+                .send(EventMessage::Rotate(dir.to_path_buf(), ack))
+                .await
+                .is_ok()
+            {
+                acks.push(done);
+            }
+        }
+        for done in acks {
+            let _ = done.await;
+        }
+    }
+
+    // * Batch mode hands batches out round-robin, partitions always go to the same writer.
+    // * Past the budget, or while older batches are still spooled, the batch goes to the spool
+    async fn send(&self, writer: usize, batch: Batch) -> Result<(), Batch> {
+        let writer_index = match self.mode {
+            OutputMode::Batch => self.next.fetch_add(1, Ordering::SeqCst) % NUM_WRITERS,
+            _ => writer,
+        };
+
+        let mut spool = self.spool.lock().await;
+        if spool.batches == 0 {
+            if let Some(reservation) = self.budget.try_reserve(batch_size(&batch)) {
+                drop(spool);
+                return self.deliver(writer_index, batch, reservation).await;
+            }
+        }
+        match spill(&mut spool, &self.stats, writer_index, &batch).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // * Without a spool the connection waits for the writers instead of dropping
+                error!(lines = batch.len(), error = ?e, "Spill batch, waiting for the writers");
+                drop(spool);
+                let reservation = self.budget.reserve(batch_size(&batch)).await;
+                self.deliver(writer_index, batch, reservation).await
+            }
+        }
+    }
+
+    // * A writer that gave up hands its batches to the next one, lines only drop when all did
+    async fn deliver(
+        &self,
+        writer: usize,
+        mut batch: Batch,
+        mut reservation: Reservation,
+    ) -> Result<(), Batch> {
+        let len = batch.len();
+        for offset in 0..NUM_WRITERS {
+            let index = (writer + offset) % NUM_WRITERS;
+            match self.txs[index]
+                .send(WriterMessage::Batch(batch, reservation))
+                .await
+            {
+                Ok(()) => {
+                    METRICS
+                        .queued_batches
+                        .with_label_values(&[&index.to_string()])
+                        .inc();
+                    self.stats.lines_queued.fetch_add(len, Ordering::SeqCst);
+                    self.stats.lines_sent.fetch_add(len, Ordering::SeqCst);
+                    return Ok(());
+                }
+                Err(mpsc::error::SendError(WriterMessage::Batch(returned, kept))) => {
+                    batch = returned;
+                    reservation = kept;
+                }
+                Err(_) => unreachable!(),
+            }
+        }
+        self.stats.lines_dropped.fetch_add(len, Ordering::SeqCst);
+        METRICS.dropped_lines.inc_by(len as u64);
+        Err(batch)
+    }
+
+    // * Writes spooled batches back in order while the budget has room, or waiting for it.
+    // * Only the batches spooled before the call, so a failing writer cannot keep it going
+    pub async fn drain(&self, wait: bool) {
+        let mut remaining = self.spool.lock().await.batches;
+        while remaining > 0 {
+            remaining -= 1;
+            let mut spooled = {
+                let mut spool = self.spool.lock().await;
+                match spool.peek().await {
+                    Ok(Some(spooled)) => spooled,
+                    Ok(None) => return,
+                    Err(e) => {
+                        let lost = spool.clear().await;
+                        self.stats.lines_dropped.fetch_add(lost, Ordering::SeqCst);
+                        self.stats.spool_bytes.store(0, Ordering::SeqCst);
+                        METRICS.dropped_lines.inc_by(lost as u64);
+                        METRICS.spool_bytes.set(0);
+                        report_error(&format!("Error: read spool, {} lines lost - {:?}", lost, e));
+                        return;
+                    }
+                }
+            };
+            let size = batch_size(&spooled.batch);
+            let reservation = if wait {
+                self.budget.reserve(size).await
+            } else {
+                match self.budget.try_reserve(size) {
+                    Some(reservation) => reservation,
+                    None => return,
+                }
+            };
+            let batch = std::mem::take(&mut spooled.batch);
+            // * The batch leaves the spool only after it is queued, so newer batches keep waiting
+            let _ = self.deliver(spooled.writer, batch, reservation).await;
+            let mut spool = self.spool.lock().await;
+            spool.pop(&spooled).await;
+            self.stats
+                .spool_bytes
+                .store(spool.bytes() as usize, Ordering::SeqCst);
+            METRICS.spool_bytes.set(spool.bytes() as i64);
+        }
+    }
+
+    pub async fn run_drain(self, cancel: CancellationToken) {
+        let mut ticker = interval(Duration::from_secs(DRAIN_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => self.drain(false).await,
+            }
+        }
+    }
+
+    // * Called once the connections are done, whatever still cannot be written stays in the spool
+    pub async fn close_spool(&self) {
+        self.drain(true).await;
+        let mut spool = self.spool.lock().await;
+        if spool.batches == 0 {
+            spool.remove().await;
+        } else {
+            report_error(&format!(
+                "Error: {} lines left in {}/{}",
+                spool.lines, CONTENT_DIR, SPOOL_FILE
+            ));
+        }
+    }
+}
+
+fn batch_size(batch: &[Entry]) -> usize {
+    batch
+        .iter()
+        .map(|entry| entry.line.len() + ENTRY_OVERHEAD)
+        .sum()
+}
+
+async fn spill(
+    spool: &mut Spool,
+    stats: &CaptureStats,
+    writer: usize,
+    batch: &[Entry],
+) -> std::io::Result<()> {
+    spool.push(writer, batch).await?;
+    stats.lines_spilled.fetch_add(batch.len(), Ordering::SeqCst);
+    stats
+        .spool_bytes
+        .store(spool.bytes() as usize, Ordering::SeqCst);
+    METRICS.spilled_lines.inc_by(batch.len() as u64);
+    METRICS.spool_bytes.set(spool.bytes() as i64);
+    Ok(())
+}
+
+impl BatchWriter {
+    // * Arrival time of a line, taken before it is parsed
+    pub fn clock(&self) -> (DateTime<Utc>, Duration) {
+        (Utc::now(), self.pool.started.elapsed())
+    }
+
+    pub async fn push(&mut self, stamp: Stamp, line: Vec<u8>) -> Result<(), Batch> {
+        let seq = self.pool.sequence.fetch_add(1, Ordering::SeqCst);
+        self.last = (seq, stamp.received);
+        let (writer, file) = match self.pool.mode {
+            OutputMode::Batch => (0, None),
+            mode => {
+                let (writer, file) = self.partition(mode, &line);
+                (writer, Some(file))
+            }
+        };
+        self.pending[writer].push(Entry {
+            seq,
+            stamp,
+            line,
+            file,
+        });
+        self.len += 1;
+        if self.len < BATCH_SIZE {
+            return Ok(());
+        }
+        self.send_pending().await
+    }
+
+    // * Queues the event parsed from the last line pushed for the Parquet writer
+    pub async fn record(&self, event: MarketEvent) {
+        let Some(events) = &self.pool.events else {
+            return;
+        };
+        let (seq, received) = self.last;
+        let record = EventRecord {
+            seq,
+            received,
+            event,
+        };
+        // * Events are derived from the capture, so they are dropped instead of spilled
+        let Some(reservation) = self.pool.budget.try_reserve(EVENT_SIZE) else {
+            self.pool
+                .stats
+                .events_dropped
+                .fetch_add(1, Ordering::SeqCst);
+            METRICS.dropped_events.inc();
+            return;
+        };
+        if events
+            .send(EventMessage::Record(record, reservation))
+            .await
+            .is_err()
+        {
+            self.pool
+                .stats
+                .events_dropped
+                .fetch_add(1, Ordering::SeqCst);
+            METRICS.dropped_events.inc();
+            warn!("Event dropped, the Parquet writer stopped");
+        }
+    }
+
+    // * Sends the incomplete batches, used when the session ends
+    pub async fn flush_pending(&mut self) -> Result<usize, Batch> {
+        let len = self.len;
+        if len > 0 {
+            info!(lines = len, "Sending pending batch to the writers");
+            self.send_pending().await?;
+        }
+        Ok(len)
+    }
+
+    async fn send_pending(&mut self) -> Result<(), Batch> {
+        self.len = 0;
+        let mut dropped = Vec::new();
+        for writer in 0..NUM_WRITERS {
+            if self.pending[writer].is_empty() {
+                continue;
+            }
+            let batch = std::mem::take(&mut self.pending[writer]);
+            if let Err(batch) = self.pool.send(writer, batch).await {
+                dropped.extend(batch);
+            }
+        }
+        if dropped.is_empty() {
+            Ok(())
+        } else {
+            Err(dropped)
+        }
+    }
+
+    fn partition(&mut self, mode: OutputMode, line: &[u8]) -> (usize, Arc<str>) {
+        let symbol = symbol_of(line).unwrap_or("");
+        if let Some((writer, file)) = self.partitions.get(symbol) {
+            return (*writer, Arc::clone(file));
+        }
+
+        let name = match (mode, symbol) {
+            (_, "") => "other".to_string(),
+            (OutputMode::Bucket(buckets), symbol) => {
+                format!("bucket-{}", shard_of(symbol, buckets))
+            }
+            (_, symbol) => format!("symbol-{}", normalize(symbol)),
+        };
+        let writer = shard_of(&name, NUM_WRITERS);
+        let file: Arc<str> = Arc::from(format!(
+            "{}/crystal-md-{}.txt{}",
+            CONTENT_DIR,
+            name,
+            self.pool.compression.extension()
+        ));
+        self.partitions
+            .insert(symbol.to_string(), (writer, Arc::clone(&file)));
+        (writer, file)
+    }
+}
+
+// * Second field of T/B/V/E lines, None for error codes and anything unsafe in a file name
+fn symbol_of(line: &[u8]) -> Option<&str> {
+    if line.get(1) != Some(&b':') {
+        return None;
+    }
+    let rest = line.get(2..)?;
+    let end = rest
+        .iter()
+        .position(|&b| matches!(b, b':' | b'!' | b'\r' | b'\n'))
+        .unwrap_or(rest.len());
+    let symbol = std::str::from_utf8(&rest[..end]).ok()?;
+    let valid = !symbol.is_empty()
+        && symbol.len() <= 32
+        && !symbol.bytes().all(|b| b.is_ascii_digit())
+        && symbol
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.');
+    if valid {
+        Some(symbol)
+    } else {
+        None
+    }
+}
+
+// * Entries stay here until they are flushed to the file, so a writer that gives up hands them
+// * back. Compressed files only build their frame when it is written
+struct CaptureFile {
+    writer: BufWriter<File>,
+    compression: Compression,
+    format: TimestampFormat,
+    frame: Vec<Entry>,
+    frame_size: usize,
+    // * Entries in the file buffer
+    buffered: Vec<Entry>,
+    // * Position of the last line written, the least recent file is closed first
+    used: u64,
+}
+
+impl CaptureFile {
+    fn new(file: File, compression: Compression, format: TimestampFormat) -> CaptureFile {
+        CaptureFile {
+            writer: BufWriter::new(file),
+            compression,
+            format,
+            frame: Vec::new(),
+            frame_size: 0,
+            buffered: Vec::new(),
+            used: 0,
+        }
+    }
+
+    async fn write(&mut self, entry: &Entry) -> std::io::Result<()> {
+        if self.compression != Compression::None {
+            return Ok(());
+        }
+        let line = capture::format_line(entry.seq, &entry.stamp, &entry.line, self.format);
+        self.writer.write_all(line.as_bytes()).await
+    }
+
+    // * Takes an entry once write succeeded
+    fn keep(&mut self, entry: Entry) {
+        if self.compression == Compression::None {
+            self.buffered.push(entry);
+        } else {
+            self.frame_size += entry.line.len() + ENTRY_OVERHEAD;
+            self.frame.push(entry);
+        }
+    }
+
+    // * Returns how many lines reached the file
+    async fn flush(&mut self, force: bool) -> std::io::Result<usize> {
+        if !self.frame.is_empty() && (force || self.frame_size >= MIN_FRAME_SIZE) {
+            let entries = std::mem::take(&mut self.frame);
+            let (compression, format) = (self.compression, self.format);
+            let (entries, compressed) = tokio::task::spawn_blocking(move || {
+                let mut frame = Vec::new();
+                for entry in &entries {
+                    let line = capture::format_line(entry.seq, &entry.stamp, &entry.line, format);
+                    frame.extend_from_slice(line.as_bytes());
+                }
+                (entries, compression.compress(&frame))
+            })
+            .await
+            .map_err(std::io::Error::other)?;
+            let written = match compressed {
+                Ok(compressed) => self.writer.write_all(&compressed).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                self.frame = entries;
+                return Err(e);
+            }
+            self.frame_size = 0;
+            self.buffered.extend(entries);
+        }
+        self.writer.flush().await?;
+        let flushed = self.buffered.len();
+        self.buffered.clear();
+        Ok(flushed)
+    }
+
+    fn take_entries(&mut self) -> Vec<Entry> {
+        self.frame_size = 0;
+        let mut entries = std::mem::take(&mut self.buffered);
+        entries.append(&mut self.frame);
+        entries
+    }
+}
+
+// * A writer that cannot write gives up, moving the batch it was on and everything queued to it
+// * into the spool, from where the other writers take them
+async fn run_writer(
+    i: usize,
+    mut rx: mpsc::Receiver<WriterMessage>,
+    compression: Compression,
+    format: TimestampFormat,
+    spool: Arc<Mutex<Spool>>,
+    stats: Arc<CaptureStats>,
+) {
+    let Some(failed) = write_messages(i, &mut rx, compression, format, &stats).await else {
+        return;
+    };
+    report_error(&format!(
+        "Error: writer {} stopped, spilling its batches",
+        i
+    ));
+    rx.close();
+    let queued = METRICS.queued_batches.with_label_values(&[&i.to_string()]);
+    let mut batches = vec![failed];
+    while let Some(message) = rx.recv().await {
+        if let WriterMessage::Batch(batch, _) = message {
+            queued.dec();
+            batches.push(batch);
+        }
+    }
+    let mut spool = spool.lock().await;
+    for batch in batches.into_iter().filter(|batch| !batch.is_empty()) {
+        stats.lines_queued.fetch_sub(batch.len(), Ordering::SeqCst);
+        stats.lines_sent.fetch_sub(batch.len(), Ordering::SeqCst);
+        if let Err(e) = spill(&mut spool, &stats, i, &batch).await {
+            stats.lines_dropped.fetch_add(batch.len(), Ordering::SeqCst);
+            METRICS.dropped_lines.inc_by(batch.len() as u64);
+            report_error(&format!(
+                "Error: spill {} lines from writer {} - {:?}",
+                batch.len(),
+                i,
+                e
+            ));
+        }
+    }
+}
+
+// * Returns every line not yet flushed when the writer gave up, None once the pool is dropped.
+// * Some of them may have reached the disk already, copies are skipped when the capture is read back
+async fn write_messages(
+    i: usize,
+    rx: &mut mpsc::Receiver<WriterMessage>,
+    compression: Compression,
+    format: TimestampFormat,
+    stats: &CaptureStats,
+) -> Option<Batch> {
+    let own_file: Arc<str> = Arc::from(format!(
+        "{}/crystal-md-{}.txt{}",
+        CONTENT_DIR,
+        i,
+        compression.extension()
+    ));
+    let mut files: HashMap<Arc<str>, CaptureFile> = HashMap::new();
+    // * Every file opened since the last rotation, including the ones closed to free handles
+    let mut touched: HashSet<Arc<str>> = HashSet::new();
+    let queued = METRICS.queued_batches.with_label_values(&[&i.to_string()]);
+    let mut flush_interval = interval(Duration::from_secs(FLUSH_INTERVAL));
+    let mut written: u64 = 0;
+    loop {
+        tokio::select! {
+            message = rx.recv() => {
+                let (batch, _reservation) = match message {
+                    Some(WriterMessage::Batch(batch, reservation)) => {
+                        queued.dec();
+                        (batch, reservation)
+                    }
+                    Some(WriterMessage::Rotate(dir, ack)) => {
+                        if flush_all(&mut files, true, "at rotation", stats).await.is_err() {
+                            return Some(unflushed(&mut files, Vec::new()));
+                        }
+                        files.clear();
+                        for name in touched.drain() {
+                            segments::move_into(Path::new(&*name), &dir);
+                        }
+                        let _ = ack.send(());
+                        continue;
+                    }
+                    None => {
+                        if flush_all(&mut files, true, "at shutdown", stats).await.is_err() {
+                            return Some(unflushed(&mut files, Vec::new()));
+                        }
+                        debug!("Stopping writer");
+                        return None;
+                    }
+                };
+                let mut entries = batch.into_iter();
+                while let Some(entry) = entries.next() {
+                    let name = Arc::clone(entry.file.as_ref().unwrap_or(&own_file));
+                    if !files.contains_key(&name) {
+                        // * Symbol mode can touch thousands of files, so only a few stay open
+                        if files.len() >= MAX_OPEN_FILES && close_least_used(&mut files, stats).await.is_err() {
+                            return Some(unflushed(&mut files, std::iter::once(entry).chain(entries)));
+                        }
+                        let Ok(file) = open_with_retries(&name).await else {
+                            return Some(unflushed(&mut files, std::iter::once(entry).chain(entries)));
+                        };
+                        touched.insert(Arc::clone(&name));
+                        files.insert(Arc::clone(&name), CaptureFile::new(file, compression, format));
+                    }
+                    let Some(writer) = files.get_mut(&name) else {
+                        continue;
+                    };
+                    written += 1;
+                    writer.used = written;
+
+                    let mut retries = 0;
+                    while let Err(e) = writer.write(&entry).await {
+                        METRICS.write_errors.with_label_values(&["write"]).inc();
+                        warn!(file = %name, error = ?e, received = %entry.stamp.received, retry = retries + 1, max = MAX_RETRIES, "Write to file, retrying");
+                        retries += 1;
+                        if retries >= MAX_RETRIES {
+                            error!(file = %name, error = ?e, received = %entry.stamp.received, "Max retries reached for write");
+                            return Some(unflushed(&mut files, std::iter::once(entry).chain(entries)));
+                        }
+                        METRICS.write_retries.with_label_values(&["write"]).inc();
+                        sleep(Duration::from_secs(RETRY_INTERVAL)).await;
+                    }
+                    writer.keep(entry);
+                }
+                if flush_all(&mut files, false, "at periodic flush", stats).await.is_err() {
+                    return Some(unflushed(&mut files, Vec::new()));
+                }
+            },
+            _ = flush_interval.tick() => {
+                if flush_all(&mut files, true, "", stats).await.is_err() {
+                    return Some(unflushed(&mut files, Vec::new()));
+                }
+            }
+        }
+    }
+}
+
+async fn open_with_retries(name: &str) -> Result<File, std::io::Error> {
+    let mut retries = 0;
+    loop {
+        match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(name)
+            .await
+        {
+            Ok(file) => return Ok(file),
+            Err(e) => {
+                METRICS.write_errors.with_label_values(&["open"]).inc();
+                warn!(
+                    file = name,
+                    error = ?e,
+                    retry = retries + 1,
+                    max = MAX_RETRIES,
+                    "Open file, retrying"
+                );
+                retries += 1;
+                if retries >= MAX_RETRIES {
+                    error!(file = name, error = ?e, "Max retries reached for open");
+                    return Err(e);
+                }
+                METRICS.write_retries.with_label_values(&["open"]).inc();
+                sleep(Duration::from_secs(RETRY_INTERVAL)).await;
+            }
+        }
+    }
+}
+
+fn unflushed(
+    files: &mut HashMap<Arc<str>, CaptureFile>,
+    rest: impl IntoIterator<Item = Entry>,
+) -> Batch {
+    let mut batch: Batch = files
+        .values_mut()
+        .flat_map(CaptureFile::take_entries)
+        .collect();
+    batch.extend(rest);
+    batch
+}
+
+async fn close_least_used(
+    files: &mut HashMap<Arc<str>, CaptureFile>,
+    stats: &CaptureStats,
+) -> Result<(), std::io::Error> {
+    let Some(name) = files
+        .iter()
+        .min_by_key(|(_, file)| file.used)
+        .map(|(name, _)| Arc::clone(name))
+    else {
+        return Ok(());
+    };
+    if let Some(file) = files.get_mut(&name) {
+        let flushed = flush_with_retries(file, true, "before closing a file").await?;
+        stats.lines_queued.fetch_sub(flushed, Ordering::SeqCst);
+    }
+    files.remove(&name);
+    Ok(())
+}
+
+async fn flush_all(
+    files: &mut HashMap<Arc<str>, CaptureFile>,
+    force: bool,
+    context: &str,
+    stats: &CaptureStats,
+) -> Result<(), std::io::Error> {
+    for file in files.values_mut() {
+        let flushed = flush_with_retries(file, force, context).await?;
+        stats.lines_queued.fetch_sub(flushed, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+async fn flush_with_retries(
+    file: &mut CaptureFile,
+    force: bool,
+    context: &str,
+) -> Result<usize, std::io::Error> {
+    let mut retries = 0;
+    loop {
+        let e = match file.flush(force).await {
+            Ok(flushed) => return Ok(flushed),
+            Err(e) => e,
+        };
+        METRICS.write_errors.with_label_values(&["flush"]).inc();
+        warn!(
+            error = ?e,
+            context,
+            retry = retries + 1,
+            max = MAX_RETRIES,
+            "Flush, retrying"
+        );
+        retries += 1;
+        if retries >= MAX_RETRIES {
+            error!(error = ?e, "Max retries reached for flush");
+            return Err(e);
+        }
+        METRICS.write_retries.with_label_values(&["flush"]).inc();
+        sleep(Duration::from_secs(RETRY_INTERVAL)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::temp_dir::TempDir;
+
+    fn entry(seq: u64, line: &str) -> Entry {
+        Entry {
+            seq,
+            stamp: Stamp {
+                received: Utc::now(),
+                monotonic: Duration::from_millis(seq),
+                exchange: None,
+            },
+            line: line.as_bytes().to_vec(),
+            file: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_only_the_least_recently_used_file() {
+        let dir = TempDir::new("writer-lru");
+        let mut files = HashMap::new();
+        for (name, used) in [("a", 3), ("b", 1), ("c", 2)] {
+            let path = dir.join(&format!("crystal-md-symbol-{}.txt", name));
+            let file = open_with_retries(path.to_str().unwrap()).await.unwrap();
+            let mut file = CaptureFile::new(file, Compression::None, TimestampFormat::Full);
+            file.used = used;
+            files.insert(Arc::from(name), file);
+        }
+        let stats = CaptureStats::default();
+        close_least_used(&mut files, &stats).await.unwrap();
+        let mut open: Vec<&str> = files.keys().map(|name| &**name).collect();
+        open.sort();
+        assert_eq!(open, vec!["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_compressed_lines_until_their_frame_is_written() {
+        let dir = TempDir::new("writer-frame");
+        let name = dir.join("crystal-md-0.txt.zst");
+        let file = open_with_retries(name.to_str().unwrap()).await.unwrap();
+        let mut file = CaptureFile::new(file, Compression::Zstd(3), TimestampFormat::Full);
+        for seq in 1..=3 {
+            let entry = entry(seq, "T:PETR4:103015:2:38.50!\r\n");
+            file.write(&entry).await.unwrap();
+            file.keep(entry);
+        }
+        // * A small frame waits for the timer, a writer giving up now hands the lines back
+        assert_eq!(file.flush(false).await.unwrap(), 0);
+        let seqs: Vec<u64> = file.take_entries().iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+
+        let entry = entry(4, "B:PETR4:E!\r\n");
+        file.write(&entry).await.unwrap();
+        file.keep(entry);
+        assert_eq!(file.flush(true).await.unwrap(), 1);
+        assert!(file.take_entries().is_empty());
+        assert!(std::fs::metadata(&name).unwrap().len() > 0);
+    }
+
+    #[test]
+    fn parses_the_output_mode() {
+        assert_eq!(OutputMode::parse(""), Ok(OutputMode::Batch));
+        assert_eq!(OutputMode::parse(" Symbol "), Ok(OutputMode::Symbol));
+        assert_eq!(OutputMode::parse("bucket:8"), Ok(OutputMode::Bucket(8)));
+        assert!(OutputMode::parse("bucket:0").is_err());
+        assert!(OutputMode::parse("bucket").is_err());
+        assert!(OutputMode::parse("daily").is_err());
+    }
+
+    #[test]
+    fn takes_the_symbol_for_the_partition() {
+        assert_eq!(symbol_of(b"T:PETR4:103015:2:38.50!\r\n"), Some("PETR4"));
+        assert_eq!(symbol_of(b"B:WINM24!"), Some("WINM24"));
+        assert_eq!(symbol_of(b"E:VALE3:2:Symbol not found!"), Some("VALE3"));
+        // * Error codes, banners and names unsafe in a file name stay in the writer's file
+        assert_eq!(symbol_of(b"E:1:Not authorized!"), None);
+        assert_eq!(symbol_of(b"You are connected"), None);
+        assert_eq!(symbol_of(b"T:../petr4:103015!"), None);
+        assert_eq!(symbol_of(b"T::103015!"), None);
+    }
+}
@@ -19,8 +19,6 @@ pub const KEEPALIVE: u64 = 3600; // 1h
 pub fn schedule_interval() -> Interval {
     if env::var("INTERVAL").unwrap_or_else(|_| "".to_string()) == "Everyday" {
         1.day()
-    } else if env::var("INTERVAL").unwrap_or_else(|_| "".to_string()) == "Weekdays" {
-        Weekday
     } else {
         Weekday
     }
@@ -40,3 +38,196 @@ pub fn start_time() -> String {
 pub fn stop_time() -> String {
     env::var("CHITA_STOP_TIME").unwrap_or_else(|_| "22:00".to_string())
 }
+
+// * Format: seconds without data before the Crystal connection is recycled
+pub fn idle_timeout() -> u64 {
+    env::var("CHITA_IDLE_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300)
+}
+
+// * Format: seconds, used between CHITA_MARKET_OPEN and CHITA_MARKET_CLOSE
+pub fn idle_timeout_market() -> u64 {
+    env::var("CHITA_IDLE_TIMEOUT_MARKET")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30)
+}
+
+// * Format: 13:00 (UTC+0)
+pub fn market_open() -> String {
+    env::var("CHITA_MARKET_OPEN").unwrap_or_else(|_| "13:00".to_string())
+}
+
+// * Format: 21:00 (UTC+0)
+pub fn market_close() -> String {
+    env::var("CHITA_MARKET_CLOSE").unwrap_or_else(|_| "21:00".to_string())
+}
+
+// * Format: command sent to Crystal after half the idle timeout, empty disables it
+pub fn ping_command() -> String {
+    env::var("CHITA_PING_COMMAND").unwrap_or_else(|_| "".to_string())
+}
+
+// * Format: number of concurrent Crystal connections, symbols are split among them
+pub fn connections() -> usize {
+    env::var("CHITA_CONNECTIONS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(1)
+}
+
+// * Format: number of credential sets, set k > 0 reads marketdata-username-k and marketdata-password-k
+pub fn credential_sets() -> usize {
+    env::var("CHITA_CREDENTIAL_SETS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(1)
+}
+
+// * Format: consecutive failed connections before moving to the next Crystal endpoint
+pub fn failover_threshold() -> u32 {
+    env::var("CHITA_FAILOVER_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3)
+}
+
+// * Format: seconds between checks of the primary endpoint while on a backup
+pub fn failback_interval() -> u64 {
+    env::var("CHITA_FAILBACK_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60)
+}
+
+// * Format: plain | tls
+pub fn transport() -> String {
+    env::var("CHITA_TRANSPORT").unwrap_or_else(|_| "plain".to_string())
+}
+
+// * Format: path to a PEM bundle, empty uses the Mozilla root certificates
+pub fn tls_ca_file() -> String {
+    env::var("CHITA_TLS_CA_FILE").unwrap_or_else(|_| "".to_string())
+}
+
+// * Format: path to a PEM certificate chain, empty disables client authentication
+pub fn tls_client_cert() -> String {
+    env::var("CHITA_TLS_CLIENT_CERT").unwrap_or_else(|_| "".to_string())
+}
+
+// * Format: path to a PEM private key
+pub fn tls_client_key() -> String {
+    env::var("CHITA_TLS_CLIENT_KEY").unwrap_or_else(|_| "".to_string())
+}
+
+// * Format: host name checked against the server certificate, empty uses the endpoint host
+pub fn tls_server_name() -> String {
+    env::var("CHITA_TLS_SERVER_NAME").unwrap_or_else(|_| "".to_string())
+}
+
+// * Format: socks5://[user:password@]host:port | http://[user:password@]host:port, empty connects directly
+pub fn proxy() -> String {
+    env::var("CHITA_PROXY").unwrap_or_else(|_| "".to_string())
+}
+
+// * Format: path to a file of symbols added or removed on the running session, empty disables it
+pub fn subscriptions_file() -> String {
+    env::var("CHITA_SUBSCRIPTIONS_FILE").unwrap_or_else(|_| "".to_string())
+}
+
+// * Format: batch | symbol | bucket:<n>
+pub fn output_mode() -> String {
+    env::var("CHITA_OUTPUT_MODE").unwrap_or_else(|_| "batch".to_string())
+}
+
+// * Format: rows per Parquet events file, 0 disables the Parquet output
+pub fn parquet_rows() -> usize {
+    env::var("CHITA_PARQUET_ROWS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000000)
+}
+
+// * Format: none | gzip[:<level 0-9>] | zstd[:<level 1-22>]
+pub fn compression() -> String {
+    env::var("CHITA_COMPRESSION").unwrap_or_else(|_| "none".to_string())
+}
+
+// * Format: seconds between capture segments, aligned to midnight (3600 rotates on the hour), 0 disables rotation
+pub fn rotate_interval() -> u64 {
+    env::var("CHITA_ROTATE_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+// * Format: bytes of capture kept in memory before batches spill to content/crystal-spool.bin
+pub fn buffer_bytes() -> usize {
+    env::var("CHITA_BUFFER_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(512 * 1024 * 1024)
+}
+
+// * Format: full (UTC with nanoseconds, monotonic offset and exchange time) | sequence (<sequence> HH:MM:SS.mmm) | legacy (HH:MM:SS.mmm)
+pub fn timestamp_format() -> String {
+    env::var("CHITA_TIMESTAMP_FORMAT").unwrap_or_else(|_| "full".to_string())
+}
+
+// * Format: 0.0.0.0:9185, address of the Prometheus /metrics endpoint, empty disables it
+pub fn metrics_addr() -> String {
+    env::var("CHITA_METRICS_ADDR").unwrap_or_else(|_| "0.0.0.0:9185".to_string())
+}
+
+// * Format: seconds without data from Crystal, counted while a session is capturing, before /healthz fails
+pub fn health_stall() -> u64 {
+    env::var("CHITA_HEALTH_STALL")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(600)
+}
+
+// * Format: info | debug,chita_mdc::core::writer=trace | <tracing EnvFilter directives>, overridden by RUST_LOG
+pub fn log_level() -> String {
+    env::var("RUST_LOG")
+        .or_else(|_| env::var("CHITA_LOG_LEVEL"))
+        .unwrap_or_else(|_| "info".to_string())
+}
+
+// * Format: text | json
+pub fn log_format() -> String {
+    env::var("CHITA_LOG_FORMAT").unwrap_or_else(|_| "text".to_string())
+}
+
+// * Format: logs/chita.log, also writes the logs to rotating files next to it, empty disables
+pub fn log_file() -> String {
+    env::var("CHITA_LOG_FILE").unwrap_or_default()
+}
+
+// * Format: daily | hourly | never
+pub fn log_rotation() -> String {
+    env::var("CHITA_LOG_ROTATION").unwrap_or_else(|_| "daily".to_string())
+}
+
+// * Format: rotated log files kept, 0 keeps all of them
+pub fn log_max_files() -> usize {
+    env::var("CHITA_LOG_MAX_FILES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(14)
+}
+
+// * Format: 127.0.0.1:9186, address of the admin API, keep it on localhost, empty disables it
+pub fn admin_addr() -> String {
+    env::var("CHITA_ADMIN_ADDR").unwrap_or_else(|_| "127.0.0.1:9186".to_string())
+}
+
+// * Format: bearer token required by every admin API request, empty disables the admin API
+pub fn admin_token() -> String {
+    env::var("CHITA_ADMIN_TOKEN").unwrap_or_default()
+}
@@ -0,0 +1,7 @@
+use tracing::error;
+
+// * Logs the error and sends it to Sentry
+pub fn report_error(error_message: &str) {
+    sentry::capture_error(&std::io::Error::other(error_message.to_string()));
+    error!("{}", error_message);
+}
@@ -0,0 +1,194 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use sd_notify::NotifyState;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+use tracing::error;
+
+use super::config::health_stall;
+
+// * The scheduler loop ticks every 5 seconds
+const SCHEDULER_STALL: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SessionState {
+    Idle,
+    Connecting,
+    Authenticated,
+    Streaming,
+    Uploading,
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionState::Idle => write!(f, "idle"),
+            SessionState::Connecting => write!(f, "connecting"),
+            SessionState::Authenticated => write!(f, "authenticated"),
+            SessionState::Streaming => write!(f, "streaming"),
+            SessionState::Uploading => write!(f, "uploading"),
+        }
+    }
+}
+
+struct Upload {
+    name: String,
+    at: DateTime<Utc>,
+    error: Option<String>,
+}
+
+#[derive(Default)]
+struct Health {
+    // * Idle, Connecting while a session runs, or Uploading
+    session: Option<SessionState>,
+    // * Connection index to its state, the session reports the most advanced one
+    connections: HashMap<usize, SessionState>,
+    last_upload: Option<Upload>,
+}
+
+static HEALTH: LazyLock<StdMutex<Health>> = LazyLock::new(Default::default);
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+// * Milliseconds since STARTED, kept apart from HEALTH since it is updated on every read
+static LAST_BYTE: AtomicU64 = AtomicU64::new(0);
+static SCHEDULER_TICK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn now_millis() -> u64 {
+    STARTED.elapsed().as_millis() as u64
+}
+
+fn since(millis: u64) -> Duration {
+    Duration::from_millis(now_millis().saturating_sub(millis))
+}
+
+pub fn scheduler_tick() {
+    SCHEDULER_TICK.store(now_millis(), Ordering::SeqCst);
+}
+
+pub fn feed() {
+    LAST_BYTE.store(now_millis(), Ordering::SeqCst);
+}
+
+// * The stall timer starts with the session, before the first byte arrives
+pub fn session_started() {
+    let mut health = HEALTH.lock().unwrap();
+    health.session = Some(SessionState::Connecting);
+    health.connections.clear();
+    feed();
+}
+
+// * Leaves Uploading alone, stop() ends it once the upload is done
+pub fn session_ended() {
+    let mut health = HEALTH.lock().unwrap();
+    if health.session != Some(SessionState::Uploading) {
+        health.session = Some(SessionState::Idle);
+    }
+    health.connections.clear();
+}
+
+pub fn uploading(active: bool) {
+    let mut health = HEALTH.lock().unwrap();
+    health.session = Some(if active {
+        SessionState::Uploading
+    } else {
+        SessionState::Idle
+    });
+}
+
+pub fn connection_state(index: usize, state: SessionState) {
+    HEALTH.lock().unwrap().connections.insert(index, state);
+}
+
+pub fn record_upload(name: &str, error: Option<String>) {
+    HEALTH.lock().unwrap().last_upload = Some(Upload {
+        name: name.to_string(),
+        at: Utc::now(),
+        error,
+    });
+}
+
+pub struct Report {
+    // * The process should be restarted when false
+    pub live: bool,
+    // * Capturing, or waiting for the next session
+    pub ready: bool,
+    pub body: Value,
+}
+
+pub fn report() -> Report {
+    let health = HEALTH.lock().unwrap();
+    let state = match health.session.unwrap_or(SessionState::Idle) {
+        SessionState::Connecting => health
+            .connections
+            .values()
+            .copied()
+            .max()
+            .unwrap_or(SessionState::Connecting),
+        state => state,
+    };
+
+    let tick = SCHEDULER_TICK.load(Ordering::SeqCst);
+    let scheduler = tick != u64::MAX && since(tick) < Duration::from_secs(SCHEDULER_STALL);
+    let last_byte = since(LAST_BYTE.load(Ordering::SeqCst));
+    let capturing = matches!(
+        state,
+        SessionState::Connecting | SessionState::Authenticated | SessionState::Streaming
+    );
+    let stalled = capturing && last_byte > Duration::from_secs(health_stall());
+
+    let live = scheduler && !stalled;
+    let ready = live
+        && matches!(
+            state,
+            SessionState::Idle | SessionState::Streaming | SessionState::Uploading
+        );
+    let last_upload = health.last_upload.as_ref().map(|upload| {
+        json!({
+            "name": upload.name,
+            "at": upload.at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "result": if upload.error.is_none() { "ok" } else { "error" },
+            "error": upload.error,
+        })
+    });
+
+    Report {
+        live,
+        ready,
+        body: json!({
+            "live": live,
+            "ready": ready,
+            "scheduler": if scheduler { "running" } else { "stopped" },
+            "session": state.to_string(),
+            "seconds_since_last_byte": capturing.then_some(last_byte.as_secs_f64()),
+            "last_upload": last_upload,
+        }),
+    }
+}
+
+// * Tells systemd the service is up, then pings the watchdog at half its timeout while live.
+// * Without NOTIFY_SOCKET, outside systemd, the notifications do nothing
+pub async fn run_watchdog() {
+    if let Err(e) = sd_notify::notify(&[NotifyState::Ready]) {
+        error!(error = ?e, "Notify systemd");
+    }
+    let Some(timeout) = sd_notify::watchdog_enabled() else {
+        return;
+    };
+    let mut ticker = interval(timeout / 2);
+    loop {
+        ticker.tick().await;
+        let report = report();
+        let status = format!("session {}", report.body["session"].as_str().unwrap_or(""));
+        let result = if report.live {
+            sd_notify::notify(&[NotifyState::Watchdog, NotifyState::Status(&status)])
+        } else {
+            sd_notify::notify(&[NotifyState::Status(&format!("{}, unhealthy", status))])
+        };
+        if let Err(e) = result {
+            error!(error = ?e, "Notify systemd watchdog");
+        }
+    }
+}
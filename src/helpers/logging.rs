@@ -0,0 +1,102 @@
+use std::fs::create_dir_all;
+use std::io::IsTerminal;
+use std::path::Path;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+use super::config::{log_file, log_format, log_level, log_max_files, log_rotation};
+
+const DEFAULT_LEVEL: &str = "info";
+
+// * Logs to stdout and, when CHITA_LOG_FILE is set, to rotating files.
+// * Keep the guard until main returns, dropping it flushes the file writer
+pub fn init() -> Option<WorkerGuard> {
+    let filter = EnvFilter::try_new(log_level()).unwrap_or_else(|e| {
+        eprintln!("Error: invalid log level, using {} - {}", DEFAULT_LEVEL, e);
+        EnvFilter::new(DEFAULT_LEVEL)
+    });
+    let json = match log_format().as_str() {
+        "json" => true,
+        "text" | "" => false,
+        other => {
+            eprintln!("Error: unknown log format {}, using text", other);
+            false
+        }
+    };
+
+    let (file, guard) = match open_file() {
+        Some((writer, guard)) => (Some(layer(writer, json, false)), Some(guard)),
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer(
+            std::io::stdout,
+            json,
+            std::io::stdout().is_terminal(),
+        ))
+        .with(file)
+        .init();
+    guard
+}
+
+fn layer<S, W>(writer: W, json: bool, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    if json {
+        fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed()
+    } else {
+        fmt::layer().with_ansi(ansi).with_writer(writer).boxed()
+    }
+}
+
+fn open_file() -> Option<(tracing_appender::non_blocking::NonBlocking, WorkerGuard)> {
+    let path = log_file();
+    if path.is_empty() {
+        return None;
+    }
+    let path = Path::new(&path);
+    let directory = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let prefix = path.file_name()?.to_string_lossy().into_owned();
+    let rotation = match log_rotation().as_str() {
+        "hourly" => Rotation::HOURLY,
+        "never" => Rotation::NEVER,
+        _ => Rotation::DAILY,
+    };
+
+    if let Err(e) = create_dir_all(directory) {
+        eprintln!("Error: create log folder {} - {}", directory.display(), e);
+        return None;
+    }
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(prefix);
+    if log_max_files() > 0 {
+        builder = builder.max_log_files(log_max_files());
+    }
+    match builder.build(directory) {
+        Ok(appender) => Some(tracing_appender::non_blocking(appender)),
+        Err(e) => {
+            eprintln!("Error: open log file {} - {}", path.display(), e);
+            None
+        }
+    }
+}
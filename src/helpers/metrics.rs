@@ -0,0 +1,203 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+use tracing::error;
+
+const NAMESPACE: &str = "chita";
+// * Seconds, vault calls take a few hundred milliseconds and uploads up to several minutes
+const DURATION_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+
+// * Process wide, so the counters keep growing across daily sessions as Prometheus expects
+pub struct Metrics {
+    registry: Registry,
+    pub received_bytes: IntCounter,
+    pub received_lines: IntCounter,
+    // * writer
+    pub queued_batches: IntGaugeVec,
+    // * operation: open | write | flush
+    pub write_errors: IntCounterVec,
+    pub write_retries: IntCounterVec,
+    pub buffered_bytes: IntGauge,
+    pub spilled_lines: IntCounter,
+    pub spool_bytes: IntGauge,
+    pub dropped_lines: IntCounter,
+    pub dropped_events: IntCounter,
+    pub reconnects: IntCounter,
+    // * feed
+    pub active_subscriptions: IntGaugeVec,
+    // * result: ok | error
+    pub upload_duration: HistogramVec,
+    pub vault_duration: HistogramVec,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace(NAMESPACE)
+}
+
+fn histogram(name: &str, help: &str) -> HistogramVec {
+    HistogramVec::new(
+        HistogramOpts::new(name, help)
+            .namespace(NAMESPACE)
+            .buckets(DURATION_BUCKETS.to_vec()),
+        &["result"],
+    )
+    .unwrap()
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let metrics = Metrics {
+            registry: Registry::new(),
+            received_bytes: IntCounter::with_opts(opts(
+                "received_bytes_total",
+                "Bytes of complete lines read from Crystal",
+            ))
+            .unwrap(),
+            received_lines: IntCounter::with_opts(opts(
+                "received_lines_total",
+                "Lines read from Crystal",
+            ))
+            .unwrap(),
+            queued_batches: IntGaugeVec::new(
+                opts("writer_queued_batches", "Batches queued to each writer"),
+                &["writer"],
+            )
+            .unwrap(),
+            write_errors: IntCounterVec::new(
+                opts("write_errors_total", "Failed capture file operations"),
+                &["operation"],
+            )
+            .unwrap(),
+            write_retries: IntCounterVec::new(
+                opts(
+                    "write_retries_total",
+                    "Capture file operations retried after a failure",
+                ),
+                &["operation"],
+            )
+            .unwrap(),
+            buffered_bytes: IntGauge::with_opts(opts(
+                "buffered_bytes",
+                "Bytes of lines and events held in memory",
+            ))
+            .unwrap(),
+            spilled_lines: IntCounter::with_opts(opts(
+                "spilled_lines_total",
+                "Lines written to the spool file",
+            ))
+            .unwrap(),
+            spool_bytes: IntGauge::with_opts(opts(
+                "spool_bytes",
+                "Bytes in the spool file waiting to be written back",
+            ))
+            .unwrap(),
+            dropped_lines: IntCounter::with_opts(opts(
+                "dropped_lines_total",
+                "Lines lost because no writer or spool could take them",
+            ))
+            .unwrap(),
+            dropped_events: IntCounter::with_opts(opts(
+                "dropped_events_total",
+                "Events left out of the Parquet files",
+            ))
+            .unwrap(),
+            reconnects: IntCounter::with_opts(opts(
+                "reconnects_total",
+                "Crystal connections retried after a failure",
+            ))
+            .unwrap(),
+            active_subscriptions: IntGaugeVec::new(
+                opts(
+                    "active_subscriptions",
+                    "Subscriptions confirmed by Crystal in the running session",
+                ),
+                &["feed"],
+            )
+            .unwrap(),
+            upload_duration: histogram(
+                "upload_duration_seconds",
+                "Blob Storage uploads, including the retries",
+            ),
+            vault_duration: histogram(
+                "vault_duration_seconds",
+                "Key Vault secret reads, including the retries",
+            ),
+        };
+
+        let registry = &metrics.registry;
+        registry
+            .register(Box::new(metrics.received_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.received_lines.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.queued_batches.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.write_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.write_retries.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.buffered_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.spilled_lines.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.spool_bytes.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.dropped_lines.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.dropped_events.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.reconnects.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.active_subscriptions.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.upload_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.vault_duration.clone()))
+            .unwrap();
+        metrics
+    }
+
+    // * Format: Prometheus text exposition
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!(error = %e, "Encode metrics");
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+// * Runs the call and records how long it took under its result
+pub async fn timed<T, E>(
+    histogram: &HistogramVec,
+    call: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let started = Instant::now();
+    let result = call.await;
+    let label = if result.is_ok() { "ok" } else { "error" };
+    histogram
+        .with_label_values(&[label])
+        .observe(started.elapsed().as_secs_f64());
+    result
+}
@@ -1,6 +1,12 @@
 pub mod assets;
 pub mod config;
+pub mod errors;
+pub mod health;
+pub mod logging;
+pub mod metrics;
 pub mod quotes;
 pub mod storage;
+#[cfg(test)]
+pub mod temp_dir;
 pub mod unzip;
 pub mod vault;
@@ -4,6 +4,7 @@ use std::io::copy;
 use std::path::Path;
 use std::time::Duration;
 use tokio::time::sleep;
+use tracing::{error, info, warn};
 
 const MAX_RETRIES: u8 = 30;
 
@@ -11,7 +12,7 @@ pub async fn download(url: &str, dest_path: &Path) -> Result<(), reqwest::Error>
     download_assets(url, dest_path.to_str().unwrap())
         .await
         .map_err(|e| {
-            eprintln!("Error: {}", e);
+            error!(error = %e, "Download quotes");
             e
         })
 }
@@ -29,20 +30,20 @@ async fn download_assets(url: &str, dest: &str) -> Result<(), reqwest::Error> {
                     let mut dest_file = File::create(dest).expect("Error: create file");
                     let content = response.bytes().await.expect("Error: retrieve data");
                     copy(&mut content.as_ref(), &mut dest_file).expect("Error: copy data");
-                    println!("File downloaded: {}", dest);
+                    info!(file = dest, "File downloaded");
                     return Ok(());
                 } else {
-                    println!("Error: {}", response.status());
+                    warn!(status = %response.status(), "Download file");
                 }
             }
             Err(e) => {
-                println!("Error: {}", e);
+                warn!(error = %e, "Download file");
             }
         }
         retries += 1;
-        println!("Retrying - [{}/{}]", retries, MAX_RETRIES);
+        info!(retry = retries, max = MAX_RETRIES, "Retrying download");
         sleep(Duration::from_secs(2)).await;
     }
-    println!("Error: download file - attempt {}", MAX_RETRIES);
+    error!(attempts = MAX_RETRIES, "Download file failed");
     Ok(())
 }
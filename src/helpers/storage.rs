@@ -6,11 +6,14 @@ use std::fs::{read_dir, remove_dir_all, remove_file, File, OpenOptions};
 use std::io::{BufReader, Read, Write};
 use std::path::Path;
 use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info, warn};
 use zip::write::FileOptions;
 use zip::CompressionMethod;
 use zip::ZipWriter;
 
 use crate::helpers::config::{RETRY_COUNT, RETRY_DELAY, UPLOAD_TIMEOUT_DURATION};
+use crate::helpers::health;
+use crate::helpers::metrics::{self, METRICS};
 
 pub async fn upload_to_blob(
     account: &str,
@@ -18,7 +21,51 @@ pub async fn upload_to_blob(
     file_path: &str,
     access_key: &str,
 ) -> Result<(), Box<dyn Error>> {
-    let zip_file_path = zip_md_folder(file_path)?;
+    let date = Utc::now().format("%Y-%m-%d").to_string();
+    upload_folder_to_blob(
+        account,
+        container,
+        file_path,
+        &format!("md-{}", date),
+        &date,
+        access_key,
+    )
+    .await
+}
+
+// * Zips the folder into <name>.zip and uploads it under <date>/, the folder is removed once uploaded
+pub async fn upload_folder_to_blob(
+    account: &str,
+    container: &str,
+    file_path: &str,
+    name: &str,
+    date: &str,
+    access_key: &str,
+) -> Result<(), Box<dyn Error>> {
+    let result = metrics::timed(
+        &METRICS.upload_duration,
+        upload_folder(account, container, file_path, name, date, access_key),
+    )
+    .await;
+    health::record_upload(name, result.as_ref().err().map(|e| e.to_string()));
+    result
+}
+
+async fn upload_folder(
+    account: &str,
+    container: &str,
+    file_path: &str,
+    name: &str,
+    date: &str,
+    access_key: &str,
+) -> Result<(), Box<dyn Error>> {
+    let folder = file_path.to_string();
+    let zip_name = name.to_string();
+    // * Segments are zipped while the capture is running, so keep it off the async workers
+    let zip_file_path = tokio::task::spawn_blocking(move || {
+        zip_md_folder(&folder, &zip_name).map_err(|e| e.to_string())
+    })
+    .await??;
     for attempt in 0..RETRY_COUNT {
         match timeout(
             UPLOAD_TIMEOUT_DURATION * attempt.try_into().unwrap(),
@@ -27,6 +74,7 @@ pub async fn upload_to_blob(
                 account,
                 container,
                 file_path,
+                date,
                 access_key.to_string(),
             ),
         )
@@ -37,18 +85,18 @@ pub async fn upload_to_blob(
                 return Ok(());
             }
             Ok(Err(e)) => {
-                eprintln!(
-                    "Error: attempt {} to upload blob {} - {}",
-                    attempt + 1,
-                    file_path,
-                    e
+                warn!(
+                    attempt = attempt + 1,
+                    path = file_path,
+                    error = %e,
+                    "Upload blob"
                 );
             }
             Err(_) => {
-                eprintln!(
-                    "Error: attempt {} to upload blob {} - timeout",
-                    attempt + 1,
-                    file_path
+                warn!(
+                    attempt = attempt + 1,
+                    path = file_path,
+                    "Upload blob timed out"
                 );
             }
         }
@@ -68,18 +116,18 @@ async fn upload_blob(
     account: &str,
     container: &str,
     file_path: &str,
+    date: &str,
     access_key: String,
 ) -> Result<(), Box<dyn Error>> {
-    println!("Sending {} to the Blob Storage", file_path);
+    info!(path = file_path, "Sending to the Blob Storage");
 
     let blob_name = &zip_file_path;
-    let date = Utc::now().format("%Y-%m-%d").to_string();
 
     let storage_credentials = StorageCredentials::access_key(account.to_string(), access_key);
     let blob_client = ClientBuilder::new(account, storage_credentials)
-        .blob_client(container, date + "/" + blob_name);
+        .blob_client(container, date.to_string() + "/" + blob_name);
 
-    let mut file = File::open(&zip_file_path)?;
+    let mut file = File::open(zip_file_path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
 
@@ -89,30 +137,30 @@ async fn upload_blob(
         .await
     {
         Ok(_) => {
-            println!("File {} sent to the Blob Storage", zip_file_path);
-            remove_file(&zip_file_path)?;
-            println!("Removed file: {}", zip_file_path);
+            info!(file = %zip_file_path, "Sent to the Blob Storage");
+            remove_file(zip_file_path)?;
+            debug!(file = %zip_file_path, "Removed file");
             Ok(())
         }
         Err(e) => {
-            eprintln!("Error: upload blob: {:?}", e);
-            remove_file(&zip_file_path)?;
-            println!("Removed file: {}", zip_file_path);
+            error!(file = %zip_file_path, error = ?e, "Upload blob");
+            remove_file(zip_file_path)?;
+            debug!(file = %zip_file_path, "Removed file");
             Err(Box::new(e))
         }
     }
 }
 
 // TODO: Zip files individually to reduce the chance of ZIP64 corruption
-fn zip_md_folder(folder_path: &str) -> Result<String, Box<dyn Error>> {
+fn zip_md_folder(folder_path: &str, name: &str) -> Result<String, Box<dyn Error>> {
     let path = Path::new(folder_path);
-    let date = Utc::now().format("%Y-%m-%d").to_string();
-    let zip_file_name = format!("{}-{}.zip", "md", date);
+    let zip_file_name = format!("{}.zip", name);
     let zip_file_path = format!("./{}", zip_file_name);
 
     let zip_file = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(&zip_file_path)?;
     let mut zip = ZipWriter::new(zip_file);
     let options: FileOptions<()> =
@@ -121,18 +169,44 @@ fn zip_md_folder(folder_path: &str) -> Result<String, Box<dyn Error>> {
     let buffer_size = 4096;
     let mut buffer = vec![0; buffer_size];
 
-    for entry in read_dir(folder_path)? {
-        let entry = entry?;
-        let file_path = entry.path();
+    // * Segment folders that could not be uploaded during the day are zipped with their path
+    let mut files = Vec::new();
+    let mut folders = vec![path.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        for entry in read_dir(folder)? {
+            let entry_path = entry?.path();
+            if entry_path.is_dir() {
+                folders.push(entry_path);
+            } else {
+                files.push(entry_path);
+            }
+        }
+    }
+
+    for file_path in files {
         if file_path.is_file() {
             let file = File::open(&file_path)?;
             let mut reader = BufReader::new(file);
 
             let file_name = file_path
-                .strip_prefix(&path)?
+                .strip_prefix(path)?
                 .to_str()
                 .ok_or("Error: convert file path to str")?;
-            zip.start_file(file_name, options)?;
+            // * Capture files compressed while written are stored as they are
+            let compressed = matches!(
+                file_path
+                    .extension()
+                    .and_then(|extension| extension.to_str()),
+                Some("gz" | "zst")
+            );
+            if compressed {
+                zip.start_file(
+                    file_name,
+                    options.compression_method(CompressionMethod::Stored),
+                )?;
+            } else {
+                zip.start_file(file_name, options)?;
+            }
 
             loop {
                 let bytes_read = reader.read(&mut buffer)?;
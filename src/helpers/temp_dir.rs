@@ -0,0 +1,29 @@
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::{Path, PathBuf};
+
+// * Folder for one test under the system temp dir, removed when dropped.
+// * Cargo runs tests in parallel, so every test passes its own name
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("chita-test-{}-{}", std::process::id(), name));
+        let _ = remove_dir_all(&path);
+        create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
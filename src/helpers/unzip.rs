@@ -1,6 +1,7 @@
 use std::fs::{create_dir_all, File};
 use std::io::copy;
 use std::path::Path;
+use tracing::{error, info};
 use zip::read::ZipArchive;
 
 use crate::helpers::assets;
@@ -15,7 +16,7 @@ pub fn process_assets(
 ) -> Result<(), std::io::Error> {
     let asset_names = assets::extract_asset_names(unzipped_file_path.to_str().unwrap())?;
     assets::save_asset_names(&asset_names, asset_names_file_path.to_str().unwrap()).map_err(|e| {
-        eprintln!("Error: save asset names - {}", e);
+        error!(error = %e, "Save asset names");
         e
     })
 }
@@ -33,12 +34,12 @@ fn unzip_file(src: &str, dest: &str) {
         } else {
             if let Some(p) = unz_path.parent() {
                 if !p.exists() {
-                    create_dir_all(&p).expect("Error: create directory");
+                    create_dir_all(p).expect("Error: create directory");
                 }
             }
             let mut outfile = File::create(&unz_path).expect("Error: create file");
             copy(&mut file, &mut outfile).expect("Error: copy file");
         }
     }
-    println!("Unzipped to: {}", dest);
+    info!(dest, "Unzipped");
 }
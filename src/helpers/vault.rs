@@ -2,12 +2,25 @@ use azure_security_keyvault::KeyvaultClient;
 use std::error::Error;
 use tokio::time::sleep;
 use tokio::time::timeout;
+use tracing::{debug, warn};
 
 use super::config::RETRY_COUNT;
 use super::config::RETRY_DELAY;
 use super::config::TIMEOUT_DURATION;
+use super::metrics::{self, METRICS};
 
 pub async fn get_secret(secret_name: &str, vault_url: &str) -> Result<String, Box<dyn Error>> {
+    metrics::timed(
+        &METRICS.vault_duration,
+        get_secret_with_retries(secret_name, vault_url),
+    )
+    .await
+}
+
+async fn get_secret_with_retries(
+    secret_name: &str,
+    vault_url: &str,
+) -> Result<String, Box<dyn Error>> {
     for attempt in 0..RETRY_COUNT {
         match timeout(
             TIMEOUT_DURATION * attempt.try_into().unwrap(),
@@ -17,19 +30,15 @@ pub async fn get_secret(secret_name: &str, vault_url: &str) -> Result<String, Bo
         {
             Ok(Ok(secret)) => return Ok(secret),
             Ok(Err(e)) => {
-                eprintln!(
-                    "Error: attempt {} to get secret {} - {}",
-                    attempt + 1,
-                    secret_name,
-                    e
+                warn!(
+                    attempt = attempt + 1,
+                    secret = secret_name,
+                    error = %e,
+                    "Get secret"
                 );
             }
             Err(_) => {
-                eprintln!(
-                    "Error: attempt {} to get secret {} - timeout",
-                    attempt + 1,
-                    secret_name
-                );
+                warn!(attempt = attempt + 1, secret = secret_name, "Get secret timed out");
             }
         }
         if attempt < RETRY_COUNT - 1 {
@@ -44,9 +53,9 @@ pub async fn get_secret(secret_name: &str, vault_url: &str) -> Result<String, Bo
 }
 
 async fn get_vault_secret(secret_name: &str, vault_url: &str) -> Result<String, Box<dyn Error>> {
-    println!("Getting: {}", secret_name);
+    debug!(secret = secret_name, "Getting secret");
     let credential = azure_identity::create_credential()?;
-    println!("{}",vault_url);
+    debug!(vault_url, "Key Vault");
     let client = KeyvaultClient::new(vault_url, credential)
         .map_err(|e| format!("Error: create KeyvaultClient: {}", e))?
         .secret_client();
@@ -4,14 +4,33 @@ mod core;
 mod helpers;
 mod tasks;
 
+use crate::core::{admin, replay, server};
 use crate::tasks::task_scheduler;
 use helpers::{
-    config::{vault_url, KEEPALIVE, SENTRY_DSN},
-    vault,
+    config::{admin_addr, admin_token, metrics_addr, vault_url, KEEPALIVE, SENTRY_DSN},
+    health, logging, vault,
 };
+use tracing::error;
 
 #[tokio::main]
 async fn main() {
+    let _log_guard = logging::init();
+
+    // * Usage: chita-mdc replay <content folder | crystal-md file | md-YYYY-MM-DD.zip> [realtime | max | <factor>]
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("replay") {
+        let Some(path) = args.get(2) else {
+            eprintln!("Usage: chita-mdc replay <path> [realtime | max | <factor>]");
+            std::process::exit(2);
+        };
+        let speed = args.get(3).map(String::as_str).unwrap_or("max");
+        if let Err(e) = replay::run(path, speed).await {
+            error!(error = %e, "replay failed");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let sentry_dsn = vault::get_secret(SENTRY_DSN, &vault_url()).await.unwrap();
 
     let _guard = sentry::init((
@@ -23,6 +42,10 @@ async fn main() {
         },
     ));
 
+    tokio::spawn(server::serve(metrics_addr()));
+    tokio::spawn(admin::serve(admin_addr(), admin_token()));
+    tokio::spawn(health::run_watchdog());
+
     tokio::spawn(async {
         task_scheduler::start().await;
     });
@@ -1,11 +1,21 @@
-use crate::core::{app, crystal};
+use crate::core::app;
 use crate::helpers::config::{schedule_interval, start_time, stop_time};
-use clokwerk::{Job, Scheduler};
+use crate::helpers::health;
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use clokwerk::{Interval, Job, Scheduler};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 
 pub async fn start() {
+    // * A restart inside the window, after a crash or a watchdog kill, resumes the capture now
+    // * instead of at the next START_TIME
+    if in_capture_window(Utc::now(), &start_time(), &stop_time(), schedule_interval()) {
+        tokio::spawn(async {
+            app::run().await;
+        });
+    }
+
     let scheduler = Arc::new(Mutex::new(Scheduler::with_tz(chrono::Utc)));
     let scheduler_clone = Arc::clone(&scheduler);
 
@@ -28,7 +38,7 @@ pub async fn start() {
             .at(&stop_time())
             .run(|| {
                 tokio::spawn(async {
-                    crystal::stop().await;
+                    app::stop().await;
                 });
             });
     }
@@ -39,7 +49,89 @@ pub async fn start() {
                 let mut scheduler = scheduler_clone.lock().await;
                 scheduler.run_pending();
             }
+            health::scheduler_tick();
             sleep(Duration::from_secs(5)).await;
         }
     });
 }
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .ok()
+}
+
+fn in_capture_window(now: DateTime<Utc>, start: &str, stop: &str, interval: Interval) -> bool {
+    let (Some(start), Some(stop)) = (parse_time(start), parse_time(stop)) else {
+        return false;
+    };
+    let time = now.time();
+    let today = now.date_naive();
+    // * The day the window opened, the previous one when it crosses midnight
+    let opened = if start <= stop {
+        if time < start || time >= stop {
+            return false;
+        }
+        today
+    } else if time >= start {
+        today
+    } else if time < stop {
+        today.pred_opt().unwrap_or(today)
+    } else {
+        return false;
+    };
+    match interval {
+        Interval::Weekday => !matches!(opened.weekday(), Weekday::Sat | Weekday::Sun),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // * 2024-05-02 is a Thursday
+    fn at(day: u32, h: u32, m: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, day, h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn resumes_inside_a_same_day_window() {
+        let inside = |now| in_capture_window(now, "11:00", "22:00", Interval::Weekday);
+        assert!(inside(at(2, 11, 0)));
+        assert!(inside(at(2, 21, 59)));
+        assert!(!inside(at(2, 10, 59)));
+        assert!(!inside(at(2, 22, 0)));
+        // * Saturday only runs every day
+        assert!(!inside(at(4, 12, 0)));
+        assert!(in_capture_window(
+            at(4, 12, 0),
+            "11:00",
+            "22:00",
+            Interval::Days(1)
+        ));
+    }
+
+    #[test]
+    fn resumes_inside_a_window_across_midnight() {
+        let inside = |now| in_capture_window(now, "22:00", "02:00:00", Interval::Weekday);
+        assert!(inside(at(3, 23, 0)));
+        // * Opened on Friday night
+        assert!(inside(at(4, 1, 0)));
+        assert!(!inside(at(4, 23, 0)));
+        // * Opened on Sunday night
+        assert!(!inside(at(6, 1, 0)));
+        assert!(!inside(at(3, 3, 0)));
+    }
+
+    #[test]
+    fn ignores_invalid_times() {
+        assert!(!in_capture_window(
+            at(2, 12, 0),
+            "11h",
+            "22:00",
+            Interval::Days(1)
+        ));
+    }
+}